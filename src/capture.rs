@@ -0,0 +1,60 @@
+//! Function scopes, which keep track of the bindings they capture from outside
+
+use crate::*;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+
+impl<'a, K, V, S> ScopedMap<'a, K, V, S> {
+    /// Makes a new scope that's the boundary of a function
+    ///
+    /// When a lookup from inside it (including from any scope made from it) finds a binding from
+    /// outside it, the key is recorded as captured. If it's inside another function scope, and the
+    /// binding is from outside that one too, it's captured by both.
+    pub fn new_function_scope(&self) -> ScopedMap<'_, K, V, S> {
+        let mut result = self.new_scope();
+        result.captures = Some(Captures {
+            generation: result.generation,
+            outer: result.outer_captures.take(),
+            entries: RefCell::new(Vec::new()),
+            seen: RefCell::new(HashSet::new()),
+            _marker: PhantomData,
+        });
+        result
+    }
+
+    /// The keys captured by this function scope, in the order they were first looked up
+    ///
+    /// Empty if this isn't a function scope.
+    pub fn captured(&self) -> Vec<&K> {
+        let captures = match &self.captures {
+            Some(captures) => captures,
+            None => return Vec::new(),
+        };
+        let entries = captures.entries.borrow();
+        entries
+            .iter()
+            .map(|ptr| {
+                // SAFETY: it's an entry from outside this scope, so it lives for 'a
+                let entry: &'a Entry<'a, K, V> = unsafe { ptr.cast().as_ref() };
+                &entry.key
+            })
+            .collect()
+    }
+
+    /// The captures of the innermost function scope this is in
+    pub(crate) fn function(&self) -> Option<&Captures<'_, K, V>> {
+        self.captures.as_ref().or(self.outer_captures)
+    }
+}
+
+impl<'a, K, V> Captures<'a, K, V> {
+    /// Records the entry as captured, if it isn't already
+    pub fn record(&self, entry: &Entry<'a, K, V>) {
+        let ptr = NonNull::from(entry).cast();
+        if self.seen.borrow_mut().insert(ptr) {
+            self.entries.borrow_mut().push(ptr);
+        }
+    }
+}
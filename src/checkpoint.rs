@@ -0,0 +1,152 @@
+//! Checkpoints, for undoing changes to a scope without making a new one
+
+use crate::*;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_MAP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A fresh id for a new map
+pub(crate) fn next_map_id() -> u64 {
+    NEXT_MAP_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A saved state of a scope, from `ScopedMap::checkpoint`
+///
+/// It's outstanding until it's rolled back to or committed. Until then, the bindings it saved are
+/// frozen, so they're copied rather than changed in place.
+pub struct Checkpoint<'a, K, V> {
+    map_id: u64,
+    /// The generation it was made at, which is its place in the map's `checkpoints`
+    generation: u32,
+    root: ItemRep<'a, K, V>,
+    len: usize,
+}
+
+impl<'a, K, V> fmt::Debug for Checkpoint<'a, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Checkpoint")
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl<'a, K, V, S> ScopedMap<'a, K, V, S> {
+    /// Saves the current state of this scope, to go back to with `rollback_to`
+    ///
+    /// Until it's rolled back to or committed, everything in the scope so far is frozen, so
+    /// changing it copies it instead, like a binding from an outer scope. It still counts as part
+    /// of this scope, though, so inserting a key that's bound in it gives
+    /// `InsertOutcome::Redefined`.
+    pub fn checkpoint(&mut self) -> Checkpoint<'a, K, V> {
+        self.generation += 1;
+        self.checkpoints.push(self.generation);
+        Checkpoint {
+            map_id: self.id,
+            generation: self.generation,
+            root: self.root.clone(),
+            len: self.len,
+        }
+    }
+
+    /// Undoes every insert and remove made in this scope since the checkpoint
+    ///
+    /// Checkpoints made after it are undone too, so they can't be rolled back to or committed
+    /// any more. Lookups made since then still count for usage tracking and captures. The memory
+    /// used since the checkpoint isn't reclaimed until the scope is dropped, since `SubArena`
+    /// can't free single items.
+    ///
+    /// Panics if the checkpoint is from a different map, or was undone by rolling back to an
+    /// older one.
+    pub fn rollback_to(&mut self, checkpoint: Checkpoint<'a, K, V>) {
+        let index = self.outstanding(&checkpoint);
+        self.checkpoints.truncate(index);
+        // Everything in the checkpoint is from an older generation than any since, so it hasn't
+        // been changed
+        self.root = checkpoint.root;
+        self.len = checkpoint.len;
+    }
+
+    /// Keeps the changes made since the checkpoint, and lets go of it
+    ///
+    /// Once no checkpoint is outstanding, the bindings in this scope can be changed in place
+    /// again.
+    ///
+    /// Panics like `rollback_to`.
+    pub fn commit(&mut self, checkpoint: Checkpoint<'a, K, V>) {
+        let index = self.outstanding(&checkpoint);
+        self.checkpoints.remove(index);
+    }
+
+    /// Where the checkpoint is in `checkpoints`, checking it's still there
+    fn outstanding(&self, checkpoint: &Checkpoint<'a, K, V>) -> usize {
+        assert_eq!(
+            checkpoint.map_id, self.id,
+            "checkpoint is from a different map"
+        );
+        self.checkpoints
+            .iter()
+            .rposition(|&generation| generation == checkpoint.generation)
+            .expect("checkpoint was undone by rolling back to an older one")
+    }
+}
+
+impl<'a, K, V, S> ScopedMap<'a, K, V, S> {
+    /// Starts making changes to this scope that are undone unless they're committed
+    ///
+    /// This is for speculative work: make the changes through the `Transaction`, and call
+    /// `commit` to keep them. They're made straight in this scope, not a new one, so committing
+    /// doesn't have to copy anything.
+    pub fn transaction(&mut self) -> Transaction<'_, 'a, K, V, S> {
+        let checkpoint = self.checkpoint();
+        Transaction {
+            map: self,
+            checkpoint: Some(checkpoint),
+        }
+    }
+}
+
+/// Changes to a scope that are rolled back when it's dropped, unless they're committed first
+///
+/// It derefs to the map being changed.
+pub struct Transaction<'m, 'a, K, V, S> {
+    map: &'m mut ScopedMap<'a, K, V, S>,
+    /// Taken when it's committed
+    checkpoint: Option<Checkpoint<'a, K, V>>,
+}
+
+impl<'m, 'a, K, V, S> Transaction<'m, 'a, K, V, S> {
+    /// Keeps the changes
+    ///
+    /// The bindings from before the transaction can be changed in place again afterwards, unless
+    /// there's another checkpoint of the scope outstanding.
+    pub fn commit(mut self) {
+        let checkpoint = self.checkpoint.take().unwrap();
+        self.map.commit(checkpoint);
+    }
+
+    /// Undoes the changes -- the same as dropping it
+    pub fn rollback(self) {}
+}
+
+impl<'m, 'a, K, V, S> Deref for Transaction<'m, 'a, K, V, S> {
+    type Target = ScopedMap<'a, K, V, S>;
+    fn deref(&self) -> &Self::Target {
+        self.map
+    }
+}
+
+impl<'m, 'a, K, V, S> DerefMut for Transaction<'m, 'a, K, V, S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.map
+    }
+}
+
+impl<'m, 'a, K, V, S> Drop for Transaction<'m, 'a, K, V, S> {
+    fn drop(&mut self) {
+        if let Some(checkpoint) = self.checkpoint.take() {
+            self.map.rollback_to(checkpoint);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Comparing two maps from the same base
+
+use crate::iter::Entries;
+use crate::*;
+use std::ptr;
+
+/// A difference between two maps, as returned by `ScopedMap::diff`
+///
+/// Each value is `None` if it's lazy and hasn't been computed, since diffing doesn't compute it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Difference<'m, K, V> {
+    /// Bound in the new map but not the old one
+    Added(&'m K, Option<&'m V>),
+    /// Bound in the old map but not the new one
+    Removed(&'m K, Option<&'m V>),
+    /// Bound to different bindings in the two maps -- the old value, then the new one
+    Changed(&'m K, Option<&'m V>, Option<&'m V>),
+}
+
+impl<'a, K, V, S> ScopedMap<'a, K, V, S>
+where
+    K: Eq,
+{
+    /// Finds the bindings that differ between this map and `other`
+    ///
+    /// Parts of the maps that are shared are skipped, so this is fast for maps in the same branch
+    /// of scopes. Bindings are compared by identity, not by value: re-inserting an equal value
+    /// still counts as a change.
+    ///
+    /// Panics if the maps aren't from the same `ScopedMapBase`.
+    pub fn diff<'m>(&'m self, other: &'m Self) -> impl Iterator<Item = Difference<'m, K, V>> {
+        assert!(
+            ptr::eq(self.hasher, other.hasher),
+            "can only diff maps from the same ScopedMapBase"
+        );
+        let mut result = Vec::new();
+        diff_items(&self.root, &other.root, &mut result);
+        result.into_iter()
+    }
+}
+
+fn diff_items<'m, K: Eq, V>(
+    old: &'m ItemRep<'m, K, V>,
+    new: &'m ItemRep<'m, K, V>,
+    result: &mut Vec<Difference<'m, K, V>>,
+) {
+    if old.ptr_eq(new) {
+        return;
+    }
+    match (old.block(), new.block()) {
+        (Some(old_block), Some(new_block)) => {
+            let old_block = ItemRef::into_ref(old_block);
+            let new_block = ItemRef::into_ref(new_block);
+            for (old, new) in old_block.entries.iter().zip(new_block.entries.iter()) {
+                diff_items(old, new, result);
+            }
+        }
+        (Some(_), None) => diff_entries(Entries::new(old), Entries::new(new), false, result),
+        (None, _) => diff_entries(Entries::new(new), Entries::new(old), true, result),
+    }
+}
+
+/// Compares two subtrees, where `small` is just a chain of entries
+fn diff_entries<'m, K: Eq, V>(
+    big: Entries<'m, K, V>,
+    small: Entries<'m, K, V>,
+    small_is_old: bool,
+    result: &mut Vec<Difference<'m, K, V>>,
+) {
+    let mut small = small.map(Some).collect::<Vec<_>>();
+    let value = |entry: &'m Entry<'m, K, V>| entry.value.computed();
+    for big_entry in big {
+        let matching = small
+            .iter_mut()
+            .find(|e| matches!(e, Some(e) if e.key == big_entry.key));
+        match matching.and_then(Option::take) {
+            Some(small_entry) if ptr::eq(small_entry, big_entry) => {}
+            Some(small_entry) => {
+                let (old, new) = if small_is_old {
+                    (small_entry, big_entry)
+                } else {
+                    (big_entry, small_entry)
+                };
+                result.push(Difference::Changed(&new.key, value(old), value(new)));
+            }
+            None if small_is_old => {
+                result.push(Difference::Added(&big_entry.key, value(big_entry)))
+            }
+            None => result.push(Difference::Removed(&big_entry.key, value(big_entry))),
+        }
+    }
+    for small_entry in small.into_iter().flatten() {
+        if small_is_old {
+            result.push(Difference::Removed(&small_entry.key, value(small_entry)));
+        } else {
+            result.push(Difference::Added(&small_entry.key, value(small_entry)));
+        }
+    }
+}
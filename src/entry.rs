@@ -0,0 +1,213 @@
+//! An entry API, for doing a lookup and then an insert without hashing twice
+
+use crate::map::OldBinding;
+use crate::*;
+use std::hash::{BuildHasher, Hash};
+
+impl<'a, K, V, S: 'a> ScopedMap<'a, K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// Looks up the key, for inserting or changing it afterwards without hashing it again
+    ///
+    /// Inserting through the entry still walks down the map a second time. The first walk only
+    /// reads, so an entry that's never inserted into doesn't copy anything.
+    pub fn entry(&mut self, key: K) -> ScopedEntry<'_, 'a, K, V, S> {
+        let hash = Self::hash(self.hasher, &key);
+        match self.find_entry(hash, &key) {
+            Some(entry) if entry.value.is_some() => ScopedEntry::Occupied(OccupiedEntry {
+                map: self,
+                key: Some(key),
+                hash,
+                entry,
+            }),
+            _ => ScopedEntry::Vacant(VacantEntry {
+                map: self,
+                key,
+                hash,
+            }),
+        }
+    }
+}
+
+/// A key in a `ScopedMap`, which may or may not be bound
+pub enum ScopedEntry<'m, 'a, K, V, S> {
+    Occupied(OccupiedEntry<'m, 'a, K, V, S>),
+    Vacant(VacantEntry<'m, 'a, K, V, S>),
+}
+
+/// A key that's bound, either in this scope or an outer one
+pub struct OccupiedEntry<'m, 'a, K, V, S> {
+    map: &'m mut ScopedMap<'a, K, V, S>,
+    /// Only taken when the binding is copied into this scope
+    key: Option<K>,
+    hash: u64,
+    entry: ItemRef<'a, Entry<'a, K, V>>,
+}
+
+/// A key that isn't bound
+pub struct VacantEntry<'m, 'a, K, V, S> {
+    map: &'m mut ScopedMap<'a, K, V, S>,
+    key: K,
+    hash: u64,
+}
+
+impl<'m, 'a, K, V, S: 'a> ScopedEntry<'m, 'a, K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &K {
+        match self {
+            ScopedEntry::Occupied(entry) => entry.key(),
+            ScopedEntry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Binds the key to `default` if it isn't bound
+    ///
+    /// Unlike `HashMap`, this only gives a shared reference: the binding might be from an outer
+    /// scope. Use `and_modify` or `OccupiedEntry::into_mut` to change it.
+    pub fn or_insert(self, default: V) -> &'m V {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'m V {
+        match self {
+            ScopedEntry::Occupied(entry) => entry.into_ref(),
+            ScopedEntry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    pub fn or_default(self) -> &'m V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    /// Changes the value if the key is bound, copying it into this scope if it's from an outer
+    /// one
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self
+    where
+        V: Clone,
+    {
+        match self {
+            ScopedEntry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                ScopedEntry::Occupied(entry)
+            }
+            ScopedEntry::Vacant(entry) => ScopedEntry::Vacant(entry),
+        }
+    }
+}
+
+impl<'m, 'a, K, V, S: 'a> OccupiedEntry<'m, 'a, K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &K {
+        &self.entry.key
+    }
+
+    pub fn get(&self) -> &V {
+        self.entry.value.as_ref().unwrap()
+    }
+
+    pub fn into_ref(self) -> &'m V {
+        ItemRef::into_ref(self.entry).value.as_ref().unwrap()
+    }
+
+    /// Whether the binding is from an outer scope, rather than this one
+    pub fn is_inherited(&self) -> bool {
+        self.entry.generation < self.map.scope_generation
+    }
+
+    /// Gets a mutable reference to the value, copying it into this scope first if it's inherited
+    pub fn get_mut(&mut self) -> &mut V
+    where
+        V: Clone,
+    {
+        self.make_owned()
+    }
+
+    /// Like `get_mut`, but for the whole lifetime of the entry
+    pub fn into_mut(mut self) -> &'m mut V
+    where
+        V: Clone,
+    {
+        self.make_owned();
+        // SAFETY: we use the right generation, and it's owned now
+        let entry = unsafe { ItemRef::promote(self.entry, self.map.frozen_below()) };
+        entry
+            .unwrap_or_else(|_| unreachable!())
+            .value
+            .as_mut()
+            .unwrap()
+    }
+
+    /// Binds the key to a new value in this scope, returning the old value if it was bound in
+    /// this scope too
+    ///
+    /// Like with `ScopedMap::insert`, stable bindings and bindings from before an outstanding
+    /// checkpoint keep their old value, so this gives `None` for them.
+    pub fn insert(&mut self, value: V) -> Option<V> {
+        if let Some(key) = self.key.take() {
+            let (entry, outcome) = self.map.insert_hashed(self.hash, key, value);
+            self.entry = entry;
+            match outcome {
+                OldBinding::Replaced(old_value) => Some(old_value),
+                _ => None,
+            }
+        } else {
+            // The key's gone, so it's been copied into this scope already
+            let frozen_below = self.map.frozen_below();
+            // SAFETY: we use the right generation
+            let entry = unsafe { ItemRef::promote_mut(&mut self.entry, frozen_below) };
+            entry.unwrap().value.replace(value)
+        }
+    }
+
+    fn make_owned(&mut self) -> &mut V
+    where
+        V: Clone,
+    {
+        // Bindings from before an outstanding checkpoint can't be changed in place either
+        if self.entry.generation < self.map.frozen_below() || self.entry.stable {
+            let key = self.key.take().unwrap();
+            let old_entry = ItemRef::into_ref(ItemRef::copy(&self.entry));
+            self.entry = self.map.copy_into_scope(self.hash, key, old_entry);
+        }
+        let frozen_below = self.map.frozen_below();
+        // SAFETY: we use the right generation
+        let entry = unsafe { ItemRef::promote_mut(&mut self.entry, frozen_below) };
+        entry.unwrap().value.as_mut().unwrap()
+    }
+}
+
+impl<'m, 'a, K, V, S: 'a> VacantEntry<'m, 'a, K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    pub fn insert(self, value: V) -> &'m mut V {
+        let (entry, _) = self.map.insert_hashed(self.hash, self.key, value);
+        // SAFETY: we use the right generation, and we just made this entry
+        let entry = unsafe { ItemRef::promote(entry, self.map.frozen_below()) };
+        entry
+            .unwrap_or_else(|_| unreachable!())
+            .value
+            .as_mut()
+            .unwrap()
+    }
+}
@@ -0,0 +1,280 @@
+//! Iterating over the bindings in a map
+
+use crate::*;
+use std::ptr;
+
+impl<'a, K, V, S> ScopedMap<'a, K, V, S>
+where
+    K: Eq,
+{
+    /// Iterates over all the bindings visible from this scope, in no particular order
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            inner: Entries::new(&self.root),
+            remaining: self.len,
+        }
+    }
+
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys { inner: self.iter() }
+    }
+
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values { inner: self.iter() }
+    }
+
+    /// Iterates over just the bindings made in this scope, in no particular order
+    ///
+    /// Parts of the map that are shared with outer scopes are skipped entirely.
+    pub fn iter_own_scope(&self) -> OwnScopeIter<'_, K, V> {
+        OwnScopeIter {
+            inner: Entries::owned(&self.root, self.scope_generation),
+        }
+    }
+
+    /// Iterates over the bindings made in this scope that haven't been looked up
+    ///
+    /// This only makes sense with usage tracking on: lookups from this scope and any scope made
+    /// from it count, as long as they were tracking usage too. The value is `None` for a lazy
+    /// binding that hasn't been computed, which it isn't just to be reported here.
+    pub fn unused(&self) -> Unused<'_, K, V> {
+        Unused {
+            inner: Entries::owned(&self.root, self.scope_generation),
+        }
+    }
+
+    /// Ends this scope, calling `on_unused` with each binding from it that was never looked up
+    ///
+    /// Like with `unused`, the value is `None` if it's lazy and hasn't been computed.
+    pub fn finish<F: FnMut(&K, Option<&V>)>(self, mut on_unused: F) {
+        for (key, value) in self.unused() {
+            on_unused(key, value);
+        }
+    }
+}
+
+impl<'m, 'a, K, V, S> IntoIterator for &'m ScopedMap<'a, K, V, S>
+where
+    K: Eq,
+{
+    type Item = (&'m K, &'m V);
+    type IntoIter = Iter<'m, K, V>;
+    fn into_iter(self) -> Iter<'m, K, V> {
+        self.iter()
+    }
+}
+
+/// Iterates over the visible entries in a subtree
+///
+/// Skips removed entries, and entries that are shadowed by an earlier one in the chain
+pub(crate) struct Entries<'m, K, V> {
+    /// If set, only visit items from that generation or later
+    min_generation: Option<u32>,
+    /// The blocks we're in the middle of, along with the next index to look at
+    blocks: Vec<(&'m Block<'m, K, V>, usize)>,
+    /// The start of the current chain
+    chain_head: Option<&'m Entry<'m, K, V>>,
+    /// The next entry in the current chain to look at
+    chain_next: Option<&'m Entry<'m, K, V>>,
+}
+
+impl<'m, K, V> Entries<'m, K, V> {
+    pub fn new(item: &'m ItemRep<'m, K, V>) -> Self {
+        Self::with_generation(item, None)
+    }
+
+    /// Only visits the entries owned by a scope that started at that generation
+    pub fn owned(item: &'m ItemRep<'m, K, V>, scope_generation: u32) -> Self {
+        Self::with_generation(item, Some(scope_generation))
+    }
+
+    fn with_generation(item: &'m ItemRep<'m, K, V>, min_generation: Option<u32>) -> Self {
+        let mut result = Self {
+            min_generation,
+            blocks: Vec::new(),
+            chain_head: None,
+            chain_next: None,
+        };
+        result.push(item);
+        result
+    }
+
+    fn push(&mut self, item: &'m ItemRep<'m, K, V>) {
+        if let Some(block) = item.block() {
+            if self.wanted(block.generation) {
+                self.blocks.push((ItemRef::into_ref(block), 0));
+            }
+        } else if let Some(entry) = item.entry() {
+            if self.wanted(entry.generation) {
+                let entry = ItemRef::into_ref(entry);
+                self.chain_head = Some(entry);
+                self.chain_next = Some(entry);
+            }
+        } else {
+            debug_assert!(item.is_empty());
+        }
+    }
+
+    fn wanted(&self, generation: u32) -> bool {
+        match self.min_generation {
+            Some(g) => generation >= g,
+            None => true,
+        }
+    }
+}
+
+impl<'m, K: Eq, V> Iterator for Entries<'m, K, V> {
+    type Item = &'m Entry<'m, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.chain_next {
+                // Owned entries always come before the rest of the chain
+                self.chain_next = entry.next.as_deref().filter(|e| self.wanted(e.generation));
+                if entry.value.is_some() && !entry.is_shadowed(self.chain_head.unwrap()) {
+                    return Some(entry);
+                }
+                continue;
+            }
+            let (block, index) = self.blocks.last_mut()?;
+            if *index == BLOCK_SIZE {
+                self.blocks.pop();
+                continue;
+            }
+            let item = &block.entries[*index];
+            *index += 1;
+            self.push(item);
+        }
+    }
+}
+
+impl<'a, K: Eq, V> Entry<'a, K, V> {
+    /// Whether there's an entry with the same key before this one in the chain starting at `head`
+    fn is_shadowed(&self, head: &Self) -> bool {
+        let mut entry = head;
+        while !ptr::eq(entry, self) {
+            if entry.key == self.key {
+                return true;
+            }
+            entry = entry.next.as_ref().expect("entry not in chain");
+        }
+        false
+    }
+}
+
+/// An iterator over the bindings visible in a `ScopedMap`
+pub struct Iter<'m, K, V> {
+    inner: Entries<'m, K, V>,
+    remaining: usize,
+}
+
+impl<'m, K: Eq, V> Iterator for Iter<'m, K, V> {
+    type Item = (&'m K, &'m V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.inner.next()?;
+        self.remaining -= 1;
+        Some((&entry.key, entry.value.as_ref().unwrap()))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'m, K: Eq, V> ExactSizeIterator for Iter<'m, K, V> {}
+
+/// An iterator over the bindings made in a single scope of a `ScopedMap`
+pub struct OwnScopeIter<'m, K, V> {
+    inner: Entries<'m, K, V>,
+}
+
+impl<'m, K: Eq, V> Iterator for OwnScopeIter<'m, K, V> {
+    type Item = (&'m K, &'m V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.inner.next()?;
+        Some((&entry.key, entry.value.as_ref().unwrap()))
+    }
+}
+
+/// An iterator over the bindings in a single scope that were never looked up, from
+/// `ScopedMap::unused`
+pub struct Unused<'m, K, V> {
+    inner: Entries<'m, K, V>,
+}
+
+impl<'m, K: Eq, V> Iterator for Unused<'m, K, V> {
+    type Item = (&'m K, Option<&'m V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.inner.find(|entry| !entry.used.get())?;
+        Some((&entry.key, entry.value.computed()))
+    }
+}
+
+/// An iterator over every binding of a single key, from `ScopedMap::lookup_all`
+pub struct LookupAll<'m, K, V> {
+    next: Option<&'m Entry<'m, K, V>>,
+}
+
+impl<'m, K, V> LookupAll<'m, K, V> {
+    pub(crate) fn new(first: Option<&'m Entry<'m, K, V>>) -> Self {
+        Self { next: first }
+    }
+}
+
+impl<'m, K: Eq, V> Iterator for LookupAll<'m, K, V> {
+    type Item = (&'m V, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.next?;
+            self.next = match entry.next.as_deref() {
+                Some(next) if !entry.sealed => next.find(&entry.key),
+                _ => None,
+            };
+            if let Some(value) = entry.value.as_ref() {
+                return Some((value, entry.depth));
+            }
+        }
+    }
+}
+
+/// An iterator over the keys visible in a `ScopedMap`
+pub struct Keys<'m, K, V> {
+    inner: Iter<'m, K, V>,
+}
+
+impl<'m, K: Eq, V> Iterator for Keys<'m, K, V> {
+    type Item = &'m K;
+
+    fn next(&mut self) -> Option<&'m K> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'m, K: Eq, V> ExactSizeIterator for Keys<'m, K, V> {}
+
+/// An iterator over the values visible in a `ScopedMap`
+pub struct Values<'m, K, V> {
+    inner: Iter<'m, K, V>,
+}
+
+impl<'m, K: Eq, V> Iterator for Values<'m, K, V> {
+    type Item = &'m V;
+
+    fn next(&mut self) -> Option<&'m V> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'m, K: Eq, V> ExactSizeIterator for Values<'m, K, V> {}
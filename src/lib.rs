@@ -9,13 +9,28 @@
 #![cfg_attr(feature = "benching", test_runner(criterion::runner))]
 
 mod arena;
+mod capture;
+mod checkpoint;
+mod diff;
+mod entry;
+mod iter;
 mod map;
+mod restrict;
+mod stack;
 mod structs;
+mod suggest;
+mod value;
 
 pub(crate) const BLOCK_BITS: usize = 4;
 pub(crate) const BLOCK_SIZE: usize = 1 << BLOCK_BITS;
 
-pub(crate) use structs::{Block, Entry, ItemRef, ItemRep};
+pub use checkpoint::{Checkpoint, Transaction};
+pub use diff::Difference;
+pub use entry::{OccupiedEntry, ScopedEntry, VacantEntry};
+pub use iter::{Iter, Keys, LookupAll, OwnScopeIter, Unused, Values};
+pub use map::{DuplicateInScope, InsertOutcome};
+pub use stack::ScopeStack;
+pub(crate) use structs::{Block, Captures, Entry, ItemRef, ItemRep};
 pub use structs::{ScopedMap, ScopedMapBase};
 
 #[cfg(test)]
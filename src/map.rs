@@ -1,12 +1,113 @@
 //! Actual map implementation
 
 use crate::arena::ArenaWrapper;
+use crate::checkpoint;
+use crate::value::Value;
 use crate::*;
 use std::borrow::Borrow;
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
 use std::hash::{BuildHasher, Hash, Hasher};
 use std::mem;
+use std::ptr::NonNull;
 use typed_arena::{Arena, SubArenaBuilder};
 
+/// What happened to the old binding of a key, from `ScopedMap::insert`
+#[derive(Debug, PartialEq, Eq)]
+pub enum InsertOutcome<'m, V> {
+    /// The key wasn't bound
+    Fresh,
+    /// The key was already bound in this scope, and this is the value it replaced
+    Replaced(V),
+    /// The key was already bound in this scope, but the old value has to be kept, so this is a
+    /// reference to it
+    ///
+    /// That's when the old binding is stable, or from before a checkpoint that's still outstanding.
+    /// It's `None` if the old value is lazy and hasn't been computed, since the old binding is
+    /// left as it is.
+    Redefined(Option<&'m V>),
+    /// The key was bound in an outer scope, and this binding now shadows it
+    ///
+    /// Like with `Redefined`, it's `None` if the old value hasn't been computed yet.
+    Shadowed(Option<&'m V>),
+}
+
+/// What an insert did with the key's old binding, before it's made into an `InsertOutcome`
+///
+/// Making the `InsertOutcome` looks at the old value, which computes it if it's lazy, so this
+/// leaves that to the callers that need it.
+pub(crate) enum OldBinding<V> {
+    Unbound,
+    Replaced(V),
+    /// It's still there, further down the chain
+    Kept,
+}
+
+/// The error from `ScopedMap::try_insert`, when the key is already bound in this scope
+///
+/// This gives back the key and value that weren't inserted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateInScope<K, V> {
+    pub key: K,
+    pub value: V,
+}
+
+impl<K, V> fmt::Display for DuplicateInScope<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("key is already bound in this scope")
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> Error for DuplicateInScope<K, V> {}
+
+/// Where to put a new entry
+pub(crate) enum EntryArena<'x, 'a, K, V> {
+    /// The current scope's arena
+    Scope(&'x ArenaWrapper<'a, Entry<'a, K, V>>),
+    /// The base's arena, for stable entries
+    Stable(&'a Arena<Entry<'a, K, V>>),
+}
+
+impl<'x, 'a, K, V> Clone for EntryArena<'x, 'a, K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'x, 'a, K, V> Copy for EntryArena<'x, 'a, K, V> {}
+
+impl<'x, 'a, K, V> EntryArena<'x, 'a, K, V> {
+    pub fn is_stable(self) -> bool {
+        matches!(self, EntryArena::Stable(_))
+    }
+
+    pub fn alloc(
+        self,
+        generation: u32,
+        depth: u32,
+        key: K,
+        value: Value<V>,
+        next: Option<ItemRef<'a, Entry<'a, K, V>>>,
+    ) -> &'a mut Entry<'a, K, V> {
+        let entry = Entry {
+            generation,
+            depth,
+            key,
+            value,
+            next,
+            used: Cell::new(false),
+            stable: self.is_stable(),
+            sealed: false,
+        };
+        match self {
+            EntryArena::Scope(arena) => arena.alloc(entry),
+            EntryArena::Stable(arena) => arena.alloc(entry),
+        }
+    }
+}
+
 impl<K, V> Default for ScopedMapBase<K, V> {
     fn default() -> Self {
         Self::new()
@@ -34,16 +135,25 @@ impl<K, V, S: BuildHasher> ScopedMapBase<K, V, S> {
         let entry_arena = ArenaWrapper::new(SubArenaBuilder::new(&self.entry_arena).build());
         ScopedMap {
             generation,
+            scope_generation: generation,
+            checkpoints: Vec::new(),
+            depth: 0,
+            id: checkpoint::next_map_id(),
             block_arena,
             entry_arena,
             root: ItemRep::empty(),
             hasher: &self.hasher,
+            len: 0,
+            track_usage: false,
+            captures: None,
+            outer_captures: None,
+            base_entry_arena: NonNull::from(&self.entry_arena).cast(),
         }
     }
 }
 
-impl<'a, K, V> ScopedMap<'a, K, V> {
-    pub fn new_scope(&self) -> ScopedMap<'_, K, V> {
+impl<'a, K, V, S> ScopedMap<'a, K, V, S> {
+    pub fn new_scope(&self) -> ScopedMap<'_, K, V, S> {
         let generation = self.generation + 1;
         let block_arena =
             ArenaWrapper::new(SubArenaBuilder::new(&*self.block_arena.inner()).build());
@@ -51,11 +161,69 @@ impl<'a, K, V> ScopedMap<'a, K, V> {
             ArenaWrapper::new(SubArenaBuilder::new(&*self.entry_arena.inner()).build());
         ScopedMap {
             generation,
+            scope_generation: generation,
+            checkpoints: Vec::new(),
+            depth: self.depth + 1,
+            id: checkpoint::next_map_id(),
             block_arena,
             entry_arena,
             root: self.root.clone(),
             hasher: self.hasher,
+            len: self.len,
+            track_usage: self.track_usage,
+            captures: None,
+            outer_captures: self.function(),
+            base_entry_arena: self.base_entry_arena,
+        }
+    }
+
+    /// Joins sibling scopes back together, making a new scope of `parent`
+    ///
+    /// The `children` should all be scopes made from `parent`. For each key bound differently in
+    /// any of the children than in the parent, `resolver` gets the key and what it's bound to in
+    /// each child, and picks what it should be bound to in the result (`None` to leave it unbound).
+    /// Everything else is shared with the parent, and only the changed keys' values are computed
+    /// if they're lazy.
+    pub fn merge_from<'p, F>(
+        parent: &'p Self,
+        children: &[&ScopedMap<'p, K, V, S>],
+        mut resolver: F,
+    ) -> ScopedMap<'p, K, V, S>
+    where
+        K: Hash + Eq + Clone,
+        S: BuildHasher,
+        F: FnMut(&K, &[Option<&V>]) -> Option<V>,
+    {
+        let mut seen = HashSet::new();
+        let mut changed = Vec::new();
+        for child in children {
+            for difference in parent.diff(child) {
+                let key = match difference {
+                    Difference::Added(k, _)
+                    | Difference::Removed(k, _)
+                    | Difference::Changed(k, _, _) => k,
+                };
+                if seen.insert(key) {
+                    changed.push(key);
+                }
+            }
+        }
+
+        let mut result = parent.new_scope();
+        let mut values = Vec::with_capacity(children.len());
+        for key in changed {
+            values.clear();
+            values.extend(children.iter().map(|child| child.lookup_untracked(key)));
+            match resolver(key, &values) {
+                Some(value) => {
+                    result.insert(key.clone(), value);
+                }
+                None => {
+                    result.remove(key);
+                }
+            }
         }
+        result
     }
 }
 
@@ -64,87 +232,575 @@ where
     K: Hash + Eq,
     S: BuildHasher,
 {
+    /// The number of bindings visible from this scope
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many scopes deep this is -- the map from `make_map` is at depth 0
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Turns usage tracking on or off for this scope, and any scopes made from it afterwards
+    ///
+    /// While it's on, the lookup methods mark the binding they find as used, for `unused` and
+    /// `finish` to report the rest.
+    pub fn set_track_usage(&mut self, track_usage: bool) {
+        self.track_usage = track_usage;
+    }
+
     pub fn lookup<'map, 'key, Q>(&'map self, key: &'key Q) -> Option<&'map V>
     where
         K: Borrow<Q>,
         Q: Hash + Eq,
     {
         let hash = Self::hash(&self.hasher, key);
-        let entry = if let Some(block) = self.root.block() {
-            ItemRef::into_ref(block).get_entry_imm(hash)?
+        let entry = self.get_chain(hash)?.find(key)?;
+        self.note_use(entry);
+        entry.value.as_ref()
+    }
+
+    /// Like `lookup`, but never marks the binding as used
+    fn lookup_untracked<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let hash = Self::hash(self.hasher, key);
+        self.get_chain(hash)?.lookup(key)
+    }
+
+    /// Looks up the key, along with the depth of the scope it was bound in
+    pub fn lookup_with_depth<Q>(&self, key: &Q) -> Option<(&V, u32)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let hash = Self::hash(self.hasher, key);
+        let entry = self.get_chain(hash)?.find(key)?;
+        self.note_use(entry);
+        Some((entry.value.as_ref()?, entry.depth))
+    }
+
+    /// Looks up the key, ignoring bindings from more than `max_depth_delta` scopes out
+    ///
+    /// With a `max_depth_delta` of 0, only bindings from this scope are found.
+    pub fn lookup_within<Q>(&self, key: &Q, max_depth_delta: u32) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let hash = Self::hash(self.hasher, key);
+        let entry = self.get_chain(hash)?.find(key)?;
+        if self.depth - entry.depth <= max_depth_delta {
+            self.note_use(entry);
+            entry.value.as_ref()
+        } else {
+            None
+        }
+    }
+
+    /// Looks up the key, ignoring any binding made in this scope
+    ///
+    /// This is what the key is bound to in the enclosing scope.
+    pub fn lookup_outer<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let hash = Self::hash(self.hasher, key);
+        let mut entry = self.get_chain(hash)?.find(key)?;
+        // Bindings from this scope are always at the front of the chain
+        while entry.generation >= self.scope_generation {
+            if entry.sealed {
+                return None;
+            }
+            entry = entry.next.as_deref()?.find(key)?;
+        }
+        self.note_use(entry);
+        entry.value.as_ref()
+    }
+
+    /// Iterates over every binding of the key, from innermost to outermost, along with the depth
+    /// of the scope it was bound in
+    ///
+    /// Bindings that are hidden by `remove` are still included, since they're visible from the
+    /// scopes they were bound in.
+    pub fn lookup_all<Q>(&self, key: &Q) -> LookupAll<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let hash = Self::hash(self.hasher, key);
+        LookupAll::new(self.get_chain(hash).and_then(|chain| chain.find(key)))
+    }
+
+    /// Gets a mutable reference to the value, if it's bound in this scope
+    ///
+    /// Bindings from outer scopes can't be changed; see `make_mut` for that. Neither can bindings
+    /// from before a checkpoint that's still outstanding, since rolling back has to see them as
+    /// they were.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let hash = Self::hash(self.hasher, key);
+        let entry = self.find_entry(hash, key)?;
+        // SAFETY: we use the right generation
+        let entry = unsafe { ItemRef::promote(entry, self.frozen_below()) };
+        entry.ok()?.value.as_mut()
+    }
+
+    /// Gets a mutable reference to the value, first copying it into this scope if it's bound in
+    /// an outer one
+    ///
+    /// The outer scopes still see the old value.
+    pub fn make_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q> + Clone,
+        V: Clone,
+        Q: Hash + Eq,
+    {
+        let hash = Self::hash(self.hasher, key);
+        let entry = self.find_entry(hash, key)?;
+        // SAFETY: we use the right generation
+        let entry = match unsafe { ItemRef::promote(entry, self.frozen_below()) } {
+            Ok(entry) => entry,
+            Err(entry) => {
+                let entry = ItemRef::into_ref(entry);
+                if !entry.value.is_some() {
+                    return None;
+                }
+                let entry = self.copy_into_scope(hash, entry.key.clone(), entry);
+                // SAFETY: we use the right generation, and we just made this entry
+                unsafe { ItemRef::promote(entry, self.frozen_below()) }
+                    .unwrap_or_else(|_| unreachable!())
+            }
+        };
+        entry.value.as_mut()
+    }
+
+    /// Copies a binding that can't be changed in place into this scope, so that it can be
+    ///
+    /// The entry should be bound to that key, which should have that hash. Returns the copy.
+    pub(crate) fn copy_into_scope(
+        &mut self,
+        hash: u64,
+        key: K,
+        entry: &Entry<'a, K, V>,
+    ) -> ItemRef<'a, Entry<'a, K, V>>
+    where
+        V: Clone,
+    {
+        // Copying it reads it, so a function scope captures it
+        self.note_use(entry);
+        let value = entry.value.as_ref().unwrap().clone();
+        let (entry, _) = self.insert_hashed(hash, key, value);
+        // It's a copy of an existing binding, not a new one
+        entry.used.set(true);
+        entry
+    }
+
+    /// Removes the key from this scope, returning its value if it was bound in this scope
+    ///
+    /// Bindings from outer scopes are hidden rather than removed, so the outer scopes (and any
+    /// other scopes made from them) still see them. The same goes for stable bindings, and
+    /// bindings from before a checkpoint that's still outstanding.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q> + Clone,
+        Q: Hash + Eq,
+    {
+        let hash = Self::hash(self.hasher, key);
+        // Check first, so we don't copy the path to a key that's not there
+        let key = self.get_chain(hash)?.lookup_key(key)?.clone();
+        let frozen_below = self.frozen_below();
+        let (item, _) = Self::get_item_mut(
+            &mut self.root,
+            frozen_below,
+            self.generation,
+            &self.block_arena,
+            hash,
+        );
+        let old_entry = item.entry().unwrap();
+        self.len -= 1;
+        // SAFETY: we use the right generation
+        unsafe {
+            old_entry
+                .set(
+                    key,
+                    None.into(),
+                    EntryArena::Scope(&self.entry_arena),
+                    frozen_below,
+                    self.generation,
+                    self.depth,
+                    item,
+                )
+                .1
+        }
+    }
+
+    /// Finds the first entry in the chain for that hash with that key, which might be a removed
+    /// one
+    pub(crate) fn find_entry<Q>(&self, hash: u64, key: &Q) -> Option<ItemRef<'a, Entry<'a, K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Eq,
+    {
+        let mut rest_hash = hash;
+        let mut item = self.root.clone();
+        let mut entry = loop {
+            if let Some(block) = item.block() {
+                let index = rest_hash as usize & (BLOCK_SIZE - 1);
+                item = ItemRef::into_ref(block).entries[index].clone();
+                rest_hash >>= BLOCK_BITS;
+            } else {
+                break item.entry()?;
+            }
+        };
+        while entry.key.borrow() != key {
+            entry = ItemRef::copy(entry.next.as_ref()?);
+        }
+        Some(entry)
+    }
+
+    /// Items from before this generation can't be changed in place: they're from an outer scope,
+    /// or an outstanding checkpoint still shares them
+    pub(crate) fn frozen_below(&self) -> u32 {
+        self.checkpoints
+            .last()
+            .copied()
+            .unwrap_or(self.scope_generation)
+    }
+
+    /// Keeps track of a lookup from this scope finding the entry
+    pub(crate) fn note_use(&self, entry: &Entry<'a, K, V>) {
+        if self.track_usage {
+            entry.used.set(true);
+        }
+        if entry.value.is_some() {
+            let mut function = self.function();
+            while let Some(captures) = function {
+                if entry.generation >= captures.generation {
+                    break;
+                }
+                captures.record(entry);
+                function = captures.outer;
+            }
+        }
+    }
+
+    /// Gets the chain of entries that could contain that hash
+    pub(crate) fn get_chain(&self, hash: u64) -> Option<&Entry<'a, K, V>> {
+        if let Some(block) = self.root.block() {
+            ItemRef::into_ref(block).get_entry_imm(hash)
         } else if let Some(entry) = self.root.entry() {
-            ItemRef::into_ref(entry)
+            Some(ItemRef::into_ref(entry))
         } else {
             debug_assert!(self.root.is_empty());
-            return None;
-        };
-        entry.lookup(key)
+            None
+        }
+    }
+
+    /// Binds the key in this scope, and says what happened to its old binding
+    pub fn insert(&mut self, key: K, value: V) -> InsertOutcome<'_, V> {
+        let hash = Self::hash(self.hasher, &key);
+        let (entry, old) = self.insert_hashed(hash, key, value);
+        self.outcome(entry, old)
+    }
+
+    /// Binds the key in this scope, unless it's already bound in this scope
+    ///
+    /// Shadowing a binding from an outer scope is fine, as is re-binding a key that was removed.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<&V, DuplicateInScope<K, V>> {
+        let hash = Self::hash(self.hasher, &key);
+        match self.find_entry(hash, &key) {
+            Some(entry) if entry.generation >= self.scope_generation && entry.value.is_some() => {
+                Err(DuplicateInScope { key, value })
+            }
+            _ => {
+                let (entry, _) = self.insert_hashed(hash, key, value);
+                Ok(ItemRef::into_ref(entry).value.as_ref().unwrap())
+            }
+        }
+    }
+
+    /// Binds the key in this scope to a value that's computed the first time it's looked up
+    ///
+    /// Once it's computed, it's kept in the binding, so other scopes that share the binding don't
+    /// compute it again. Replacing or removing the binding in this scope computes it too, to give
+    /// back the old value, but shadowing or hiding it from a scope made from this one doesn't:
+    /// the `InsertOutcome` has `None` for the old value instead.
+    pub fn insert_lazy<F>(&mut self, key: K, f: F) -> InsertOutcome<'_, V>
+    where
+        F: FnOnce() -> V + 'a,
+    {
+        let hash = Self::hash(self.hasher, &key);
+        let (entry, old) = self.insert_hashed_in(None, hash, key, Value::lazy(f));
+        self.outcome(entry, old)
     }
 
-    pub fn insert<'temp>(&'temp mut self, key: K, value: V) {
-        let hash = Self::hash(&self.hasher, &key);
-        let (mut item, depth): (&'temp mut ItemRep<'a, _, _>, _) =
-            Self::get_item_mut(&mut self.root, self.generation, &self.block_arena, hash);
+    /// Binds the key in this scope, and gives back a reference to the value that lasts as long as
+    /// the map's lifetime `'a`
+    ///
+    /// The binding is kept in the `ScopedMapBase` rather than this scope, so it lasts until the
+    /// base is dropped, even once this scope is gone. It's never changed in place: inserting the
+    /// key again shadows it instead, and `get_mut` doesn't give access to it.
+    pub fn insert_stable(&mut self, key: K, value: V) -> &'a V
+    where
+        K: 'static,
+        V: 'static,
+    {
+        let hash = Self::hash(self.hasher, &key);
+        let (entry, _) =
+            self.insert_hashed_in(Some(self.stable_arena()), hash, key, Some(value).into());
+        ItemRef::into_ref(entry).value.as_ref().unwrap()
+    }
+
+    /// Looks up the key, with a reference that lasts as long as the map's lifetime `'a`
+    ///
+    /// Bindings from outer scopes already last that long. A binding from this scope has its value
+    /// moved into the base first, like with `insert_stable`. After that, the binding is stable
+    /// too: `get_mut` gives `None` for it, `remove` hides it without giving its value back, and
+    /// inserting the key again gives `InsertOutcome::Redefined`.
+    pub fn lookup_stable<Q>(&mut self, key: &Q) -> Option<&'a V>
+    where
+        K: Borrow<Q> + Clone + 'static,
+        V: 'static,
+        Q: Hash + Eq,
+    {
+        let hash = Self::hash(self.hasher, key);
+        let entry = self.find_entry(hash, key)?;
+        self.note_use(&entry);
+        entry.value.as_ref()?;
+        if entry.generation < self.scope_generation || entry.stable {
+            // It's inherited or already stable, so it lives for 'a and won't change
+            return ItemRef::into_ref(entry).value.as_ref();
+        }
+        // It's in this scope's arena, so move the value into a spare entry in the base's, and
+        // leave a pointer to it. That doesn't change what the binding looks like, even to
+        // checkpoints from before it was made
+        //
+        // SAFETY: it's from this scope, so only this map and its checkpoints can see it, and we
+        // have `&mut self`
+        let entry = unsafe { ItemRef::promote_unchecked(entry) };
+        let value = entry.value.take().unwrap();
+        let spare: &'a Entry<'a, K, V> = EntryArena::Stable(self.stable_arena()).alloc(
+            entry.generation,
+            entry.depth,
+            entry.key.clone(),
+            Some(value).into(),
+            None,
+        );
+        let value = spare.value.as_ref().unwrap();
+        // SAFETY: it's in the base's arena, so it outlives the entry, and it's never mutated
+        entry.value = unsafe { Value::moved(value) };
+        entry.stable = true;
+        Some(value)
+    }
+
+    /// The base's arena, for stable entries
+    fn stable_arena(&self) -> &'a Arena<Entry<'a, K, V>>
+    where
+        K: 'static,
+        V: 'static,
+    {
+        // SAFETY: it's the base's arena, which lives for 'a. Putting entries with shorter
+        // lifetimes in it is fine: K and V are 'static, so dropping them later can't see
+        // anything dangling, and nothing else in an entry has drop glue
+        unsafe { self.base_entry_arena.cast().as_ref() }
+    }
+
+    /// Inserts the key, which should have that hash
+    ///
+    /// Returns the entry it's now in, and what happened to the old binding
+    pub(crate) fn insert_hashed(
+        &mut self,
+        hash: u64,
+        key: K,
+        value: V,
+    ) -> (ItemRef<'a, Entry<'a, K, V>>, OldBinding<V>) {
+        self.insert_hashed_in(None, hash, key, Some(value).into())
+    }
+
+    /// Says what happened to the old binding, given the entry from `insert_hashed`
+    fn outcome(
+        &self,
+        entry: ItemRef<'a, Entry<'a, K, V>>,
+        old: OldBinding<V>,
+    ) -> InsertOutcome<'_, V> {
+        match old {
+            OldBinding::Unbound => InsertOutcome::Fresh,
+            OldBinding::Replaced(old_value) => InsertOutcome::Replaced(old_value),
+            OldBinding::Kept => {
+                // It's a new link in front of the old binding
+                let new_entry = ItemRef::into_ref(entry);
+                let old_entry = new_entry.next.as_deref().unwrap();
+                let old_entry = old_entry.find(&new_entry.key).unwrap();
+                // Don't compute a lazy value just to say what it was
+                let old_value = old_entry.value.computed();
+                if old_entry.generation >= self.scope_generation {
+                    InsertOutcome::Redefined(old_value)
+                } else {
+                    InsertOutcome::Shadowed(old_value)
+                }
+            }
+        }
+    }
+
+    /// Like `insert_hashed`, but the new entry's stable if it's given the base's arena, and the
+    /// value can be lazy
+    fn insert_hashed_in<'temp>(
+        &'temp mut self,
+        stable_arena: Option<&'a Arena<Entry<'a, K, V>>>,
+        hash: u64,
+        key: K,
+        value: Value<V>,
+    ) -> (ItemRef<'a, Entry<'a, K, V>>, OldBinding<V>) {
+        let arena = match stable_arena {
+            Some(arena) => EntryArena::Stable(arena),
+            None => EntryArena::Scope(&self.entry_arena),
+        };
+        let frozen_below = self.frozen_below();
+        let (mut item, depth): (&'temp mut ItemRep<'a, _, _>, _) = Self::get_item_mut(
+            &mut self.root,
+            frozen_below,
+            self.generation,
+            &self.block_arena,
+            hash,
+        );
         let old_item = mem::take(item);
         if let Some(old_entry) = old_item.entry() {
             let old_hash = old_entry.hash(self.hasher);
             if old_hash == hash {
+                let was_bound = matches!(old_entry.find(&key), Some(e) if e.value.is_some());
+                match (was_bound, value.is_some()) {
+                    (false, true) => self.len += 1,
+                    (true, false) => self.len -= 1,
+                    _ => {}
+                }
                 // SAFETY: we use the right generation
-                unsafe { old_entry.set(key, value, &self.entry_arena, self.generation, item) };
+                let (entry, old_value) = unsafe {
+                    old_entry.set(
+                        key,
+                        value,
+                        arena,
+                        frozen_below,
+                        self.generation,
+                        self.depth,
+                        item,
+                    )
+                };
+                let old = match old_value {
+                    Some(old_value) => OldBinding::Replaced(old_value),
+                    None if was_bound => OldBinding::Kept,
+                    None => OldBinding::Unbound,
+                };
+                (entry, old)
             } else {
-                let new_entry: &'a mut Entry<'a, K, V> = self.entry_arena.alloc(Entry {
-                    generation: self.generation,
-                    key,
-                    value,
-                    next: None,
-                });
-                // Need to make a block, and put both entries in it
-                let mut depth = depth;
-                let mut new_hash_rest = hash >> depth;
-                let mut old_hash_rest = Self::hash(&self.hasher, &old_entry.key) >> depth;
-                while depth < 64 {
-                    let mut new_block: &'a mut Block<'a, _, _> =
-                        self.block_arena.alloc(Block::empty(self.generation));
-                    let new_index = new_hash_rest as usize & (BLOCK_SIZE - 1);
-                    let old_index = old_hash_rest as usize & (BLOCK_SIZE - 1);
-                    if new_index == old_index {
-                        new_hash_rest >>= BLOCK_BITS;
-                        old_hash_rest >>= BLOCK_BITS;
-                        depth += BLOCK_BITS;
-                        *item = ItemRep::from_block(new_block);
-                        // SAFETY: we use the right generation, and explicitly bound the lifetime
-                        // shouldn't even panic, we own this block -- we just made it
-                        let new_item: &'temp mut _ = unsafe {
-                            &mut ItemRef::promote(item.block().unwrap(), self.generation)
-                                .unwrap_or_else(|_| unreachable!())
-                                .entries[new_index]
-                        };
-                        item = new_item;
-                        continue;
-                    } else {
-                        new_block.entries[old_index] =
-                            ItemRep::from_entry(ItemRef::into_ref(old_entry));
-                        new_block.entries[new_index] = ItemRep::from_entry(new_entry);
-                        *item = ItemRep::from_block(new_block);
-                        return;
-                    }
+                if value.is_some() {
+                    self.len += 1;
                 }
-                unreachable!("Hashes are both unequal and equal: {} {}", hash, old_hash);
+                let new_entry = arena.alloc(self.generation, self.depth, key, value, None);
+                let new_entry = Self::split(
+                    item,
+                    depth,
+                    self.generation,
+                    &self.block_arena,
+                    (hash, ItemRep::from_entry(new_entry)),
+                    (old_hash, old_item),
+                );
+                (new_entry.entry().unwrap(), OldBinding::Unbound)
             }
         } else {
             debug_assert!(item.is_empty());
-            let new_entry: &'a mut Entry<'a, K, V> = self.entry_arena.alloc(Entry {
-                generation: self.generation,
-                key,
-                value,
-                next: None,
-            });
+            if value.is_some() {
+                self.len += 1;
+            }
+            let new_entry = arena.alloc(self.generation, self.depth, key, value, None);
             *item = ItemRep::from_entry(new_entry);
+            (item.entry().unwrap(), OldBinding::Unbound)
         }
     }
 
+    /// Puts a chain of entries for that hash in the map, where there's no chain for it yet
+    pub(crate) fn insert_chain(&mut self, hash: u64, chain: ItemRep<'a, K, V>) {
+        let frozen_below = self.frozen_below();
+        let (item, depth) = Self::get_item_mut(
+            &mut self.root,
+            frozen_below,
+            self.generation,
+            &self.block_arena,
+            hash,
+        );
+        let old_item = mem::take(item);
+        if let Some(old_entry) = old_item.entry() {
+            let old_hash = old_entry.hash(self.hasher);
+            debug_assert_ne!(old_hash, hash);
+            Self::split(
+                item,
+                depth,
+                self.generation,
+                &self.block_arena,
+                (hash, chain),
+                (old_hash, old_item),
+            );
+        } else {
+            debug_assert!(item.is_empty());
+            *item = chain;
+        }
+    }
+
+    /// Puts two chains with different hashes in the slot, making blocks until their hashes go to
+    /// different places
+    ///
+    /// Returns the new chain's slot
+    fn split<'temp>(
+        mut item: &'temp mut ItemRep<'a, K, V>,
+        mut depth: usize,
+        generation: u32,
+        block_arena: &'temp ArenaWrapper<'a, Block<'a, K, V>>,
+        (new_hash, new_chain): (u64, ItemRep<'a, K, V>),
+        (old_hash, old_chain): (u64, ItemRep<'a, K, V>),
+    ) -> &'temp ItemRep<'a, K, V> {
+        let mut new_hash_rest = new_hash >> depth;
+        let mut old_hash_rest = old_hash >> depth;
+        while depth < 64 {
+            let new_block: &'a mut Block<'a, _, _> = block_arena.alloc(Block::empty(generation));
+            let new_index = new_hash_rest as usize & (BLOCK_SIZE - 1);
+            let old_index = old_hash_rest as usize & (BLOCK_SIZE - 1);
+            *item = ItemRep::from_block(new_block);
+            // SAFETY: we use the right generation, and explicitly bound the lifetime
+            // shouldn't even panic, we own this block -- we just made it
+            let block: &'temp mut Block<'a, _, _> = unsafe {
+                ItemRef::promote(item.block().unwrap(), generation)
+                    .unwrap_or_else(|_| unreachable!())
+            };
+            if new_index == old_index {
+                new_hash_rest >>= BLOCK_BITS;
+                old_hash_rest >>= BLOCK_BITS;
+                depth += BLOCK_BITS;
+                item = &mut block.entries[new_index];
+            } else {
+                block.entries[old_index] = old_chain;
+                block.entries[new_index] = new_chain;
+                return &block.entries[new_index];
+            }
+        }
+        unreachable!(
+            "Hashes are both unequal and equal: {} {}",
+            new_hash, old_hash
+        );
+    }
+
     #[inline]
-    fn hash<Q>(build_hasher: &S, key: &Q) -> u64
+    pub(crate) fn hash<Q>(build_hasher: &S, key: &Q) -> u64
     where
         K: Borrow<Q>,
         Q: Hash + Eq,
@@ -161,8 +817,12 @@ where
     ///  * an empty slot
     ///  * a slot with an entry owned by this generation, sharing a prefix of the hash
     ///  * a slot with an entry owned by the previous generation, sharing a prefix of the hash
+    ///
+    /// Blocks from `frozen_below` on are changed in place, and older ones are copied into new
+    /// blocks from `generation`.
     fn get_item_mut<'temp>(
         root: &'temp mut ItemRep<'a, K, V>,
+        frozen_below: u32,
         generation: u32,
         block_arena: &'temp ArenaWrapper<'a, Block<'a, K, V>>,
         hash: u64,
@@ -176,7 +836,7 @@ where
                 None => return (item, shift_amt),
             };
             // SAFETY: we use the right generation, and the lifetime is bounded to 'temp just above
-            match unsafe { ItemRef::promote(block, generation) } {
+            match unsafe { ItemRef::promote(block, frozen_below) } {
                 Ok(mutable_blk) => {
                     // We own this block -- use it
                     let index = rest_hash as usize & (BLOCK_SIZE - 1);
@@ -266,6 +926,30 @@ impl<'a, K, V> Entry<'a, K, V> {
     }
 
     fn lookup<'temp, Q>(&'temp self, key: &Q) -> Option<&'temp V>
+    where
+        K: Borrow<Q>,
+        Q: Eq,
+    {
+        self.find(key)?.value.as_ref()
+    }
+
+    /// Like `lookup`, but gets the stored key instead
+    fn lookup_key<'temp, Q>(&'temp self, key: &Q) -> Option<&'temp K>
+    where
+        K: Borrow<Q>,
+        Q: Eq,
+    {
+        let entry = self.find(key)?;
+        // Don't use `as_ref`, which would compute a lazy value
+        if entry.value.is_some() {
+            Some(&entry.key)
+        } else {
+            None
+        }
+    }
+
+    /// Finds the first entry in the chain with that key, which might be a removed one
+    pub(crate) fn find<'temp, Q>(&'temp self, key: &Q) -> Option<&'temp Self>
     where
         K: Borrow<Q>,
         Q: Eq,
@@ -273,7 +957,7 @@ impl<'a, K, V> Entry<'a, K, V> {
         let mut entry = self;
         loop {
             if key == entry.key.borrow() {
-                return Some(&entry.value);
+                return Some(entry);
             }
             match entry.next {
                 Some(ref next) => entry = next,
@@ -284,57 +968,92 @@ impl<'a, K, V> Entry<'a, K, V> {
 
     /// Possibly mutates self if it's a unique ref, and puts the updated entry in `into`
     ///
+    /// Returns the entry with the key, and the old value if it was updated in place
+    ///
     /// Safety: gotta pass the right generation
+    #[allow(clippy::too_many_arguments)]
     unsafe fn set(
         self: ItemRef<'a, Self>,
         key: K,
-        value: V,
-        arena: &ArenaWrapper<'a, Self>,
+        value: Value<V>,
+        arena: EntryArena<'_, 'a, K, V>,
+        frozen_below: u32,
         generation: u32,
+        depth: u32,
         into: &mut ItemRep<'a, K, V>,
-    ) where
+    ) -> (ItemRef<'a, Self>, Option<V>)
+    where
         K: Eq,
     {
         let mut result = None;
-        self.set_internal(key, value, arena, generation, &mut result);
+        let set_result = self.set_internal(
+            key,
+            value,
+            arena,
+            frozen_below,
+            generation,
+            depth,
+            &mut result,
+        );
         *into = ItemRep::from_entry(ItemRef::into_ref(result.unwrap()));
+        set_result
     }
 
     /// Safety: gotta pass the right generation
-    #[allow(unused_unsafe)]
+    #[allow(unused_unsafe, clippy::too_many_arguments)]
     unsafe fn set_internal(
         self: ItemRef<'a, Self>,
         key: K,
-        value: V,
-        arena: &ArenaWrapper<'a, Self>,
+        value: Value<V>,
+        arena: EntryArena<'_, 'a, K, V>,
+        frozen_below: u32,
         generation: u32,
+        depth: u32,
         mut into: &mut Option<ItemRef<'a, Self>>,
-    ) where
+    ) -> (ItemRef<'a, Self>, Option<V>)
+    where
         K: Eq,
     {
         // Currently loops thru all owned entries, in case one's the same
         // Might be faster to unconditionally add a link?
         // TODO benchmark
+        //
+        // Only bindings that aren't frozen get updated in place, and no other scope or checkpoint
+        // can see those, so the chain still has every binding that `lookup_all` could need
 
         // entry is always Some(...) when it's used
         // might be a better way to use it?
         let mut entry: Option<ItemRef<'a, Entry<'a, K, V>>> = Some(self);
 
         let entry = loop {
-            match ItemRef::promote(entry.take().unwrap(), generation) {
+            match ItemRef::promote(entry.take().unwrap(), frozen_below) {
                 Ok(mutable) => {
-                    if mutable.key == key {
+                    if mutable.key == key && arena.is_stable() {
+                        // Mutable, identical, but the new one's stable -- shadow it with a new
+                        // link instead, which takes its place
+                        let old_value = mutable.value.take();
+                        let new_entry = arena.alloc(
+                            generation,
+                            depth,
+                            key,
+                            value,
+                            Some(ItemRef::from_mut(mutable)),
+                        );
+                        *into = Some(ItemRef::from_mut(new_entry));
+                        return (ItemRef::copy(into.as_ref().unwrap()), old_value);
+                    } else if mutable.key == key {
                         // Mutable, identical -- update in place
-                        mutable.value = value;
+                        let old_value = mem::replace(&mut mutable.value, value).into_option();
                         mutable.key = key;
+                        mutable.used.set(false);
                         *into = Some(ItemRef::from_mut(mutable));
-                        return;
+                        return (ItemRef::copy(into.as_ref().unwrap()), old_value);
                     } else if let Some(next) = mutable.next.take() {
                         // Mutable, has continuation -- loop on the continuation
                         *into = Some(ItemRef::from_mut(mutable));
                         // SAFETY: the reference is unique, we just put a mutable reference there
                         into = unsafe {
-                            &mut ItemRef::promote_mut(into.as_mut().unwrap(), generation)
+                            &mut ItemRef::promote_mut(into.as_mut().unwrap(), frozen_below)
                                 .unwrap()
                                 .next
                         };
@@ -346,19 +1065,14 @@ impl<'a, K, V> Entry<'a, K, V> {
                     }
                 }
                 Err(entry_again) => {
-                    // Immutable -- add new link
+                    // Immutable (or stable) -- add new link
                     break entry_again;
                 }
             }
         };
         // add new link
-        let new_entry = arena.alloc(Entry {
-            generation,
-            key,
-            value,
-            next: Some(entry),
-        });
+        let new_entry = arena.alloc(generation, depth, key, value, Some(entry));
         *into = Some(ItemRef::from_mut(new_entry));
-        return;
+        return (ItemRef::copy(into.as_ref().unwrap()), None);
     }
 }
@@ -0,0 +1,107 @@
+//! Making new maps with only some of the bindings of an old one
+
+use crate::map::EntryArena;
+use crate::*;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::ptr;
+
+impl<'a, K, V, S: 'a> ScopedMap<'a, K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// Makes a new scope with just the given keys, bound like they are here
+    ///
+    /// Unlike a scope from `new_scope`, it doesn't share any blocks with this map, so it's only
+    /// as big as the keys in it. The bindings themselves are shared, not cloned. Keys that aren't
+    /// bound here are left out.
+    ///
+    /// Any other key isn't bound in the result at all: `lookup_outer` and `lookup_all` don't find
+    /// its bindings from this map either.
+    pub fn restrict<'k, Q, I>(&self, keys: I) -> ScopedMap<'_, K, V, S>
+    where
+        K: Borrow<Q> + Clone,
+        Q: Hash + Eq + 'k,
+        I: IntoIterator<Item = &'k Q>,
+    {
+        // The entries to keep, grouped by hash
+        let mut chains: HashMap<u64, Vec<&Entry<'a, K, V>>> = HashMap::new();
+        for key in keys {
+            let hash = Self::hash(self.hasher, key);
+            let entry = match self.get_chain(hash).and_then(|chain| chain.find(key)) {
+                Some(entry) if entry.value.is_some() => entry,
+                _ => continue,
+            };
+            let kept = chains.entry(hash).or_default();
+            if !kept.iter().any(|e| ptr::eq(*e, entry)) {
+                kept.push(entry);
+            }
+        }
+
+        let mut result = self.empty_scope();
+        for (hash, kept) in chains {
+            // They're all in the same chain, so share it from the first of them...
+            let mut tail = self.get_chain(hash).unwrap();
+            while !kept.iter().any(|e| ptr::eq(*e, tail)) {
+                tail = tail.next.as_deref().unwrap();
+            }
+            // ...and hide all the other keys in the rest of it
+            let mut chain = ItemRep::from_entry(tail);
+            let mut hidden = Vec::new();
+            let mut entry = Some(tail);
+            while let Some(e) = entry {
+                if !kept.iter().any(|k| k.key == e.key) && !hidden.contains(&&e.key) {
+                    hidden.push(&e.key);
+                    let removed = EntryArena::Scope(&result.entry_arena).alloc(
+                        result.generation,
+                        result.depth,
+                        e.key.clone(),
+                        None.into(),
+                        chain.entry(),
+                    );
+                    // It's not bound in an outer scope of the result either
+                    removed.sealed = true;
+                    chain = ItemRep::from_entry(removed);
+                }
+                entry = e.next.as_deref();
+            }
+            result.len += kept.len();
+            result.insert_chain(hash, chain);
+        }
+        result
+    }
+
+    /// Makes a new scope with just the bindings that `pred` returns true for
+    ///
+    /// Like `restrict`, it doesn't share any blocks with this map, but it does share the bindings.
+    pub fn retain<F>(&self, mut pred: F) -> ScopedMap<'_, K, V, S>
+    where
+        K: Clone,
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.restrict(self.iter().filter(|(k, v)| pred(k, v)).map(|(k, _)| k))
+    }
+
+    /// Makes a new scope with every binding from this one, with the value changed by `f`
+    pub fn map_values<F>(&self, mut f: F) -> ScopedMap<'_, K, V, S>
+    where
+        K: Clone,
+        F: FnMut(&K, &V) -> V,
+    {
+        let mut result = self.empty_scope();
+        for (key, value) in self {
+            result.insert(key.clone(), f(key, value));
+        }
+        result
+    }
+
+    /// Like `new_scope`, but with nothing in it
+    fn empty_scope(&self) -> ScopedMap<'_, K, V, S> {
+        let mut result = self.new_scope();
+        result.root = ItemRep::empty();
+        result.len = 0;
+        result
+    }
+}
@@ -0,0 +1,128 @@
+//! A stack of scopes in one owned value, for when scopes can't follow the call stack
+
+use crate::*;
+use ahash::RandomState;
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+
+/// A `ScopedMap` and all the scopes it's in, with scopes pushed and popped explicitly
+///
+/// It doesn't borrow anything, so it fits visitors and explicit-stack interpreters that can't
+/// keep a `ScopedMap` for each scope on the call stack. Popping a scope frees its arenas.
+pub struct ScopeStack<K: 'static, V: 'static, S: 'static = RandomState> {
+    /// Innermost last. Each one borrows the one before it (and the first borrows `base`), so
+    /// they're boxed to keep them in place, and always dropped innermost first
+    #[allow(clippy::vec_box)]
+    scopes: Vec<Box<ScopedMap<'static, K, V, S>>>,
+    /// Only kept for the scopes to borrow
+    _base: Box<ScopedMapBase<K, V, S>>,
+}
+
+impl<K, V> Default for ScopeStack<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> ScopeStack<K, V> {
+    pub fn new() -> Self {
+        Self::with_hasher(Default::default())
+    }
+}
+
+impl<K, V, S: BuildHasher> ScopeStack<K, V, S> {
+    pub fn with_hasher(hasher: S) -> Self {
+        let base = Box::new(ScopedMapBase::with_hasher(hasher));
+        // SAFETY: the base is boxed, so it doesn't move, and it's dropped after the scope
+        let scope = unsafe { extend(base.make_map()) };
+        Self {
+            scopes: vec![Box::new(scope)],
+            _base: base,
+        }
+    }
+}
+
+impl<K, V, S> ScopeStack<K, V, S> {
+    /// The innermost scope
+    ///
+    /// It's only a shared reference: changes go through the stack, so that the scopes it holds
+    /// can't be swapped out.
+    pub fn scope(&self) -> &ScopedMap<'_, K, V, S> {
+        self.scopes.last().unwrap()
+    }
+
+    /// Starts a new innermost scope
+    pub fn push_scope(&mut self) {
+        let new_scope = self.scopes.last().unwrap().new_scope();
+        // SAFETY: the scope it borrows is boxed, so it doesn't move, and it's popped after this
+        let new_scope = unsafe { extend(new_scope) };
+        self.scopes.push(Box::new(new_scope));
+    }
+
+    /// Ends the innermost scope, freeing everything in it
+    ///
+    /// Panics if there's only the outermost scope left.
+    pub fn pop_scope(&mut self) {
+        assert!(self.scopes.len() > 1, "can't pop the outermost scope");
+        self.scopes.pop();
+    }
+
+    fn top(&mut self) -> &mut ScopedMap<'static, K, V, S> {
+        self.scopes.last_mut().unwrap()
+    }
+}
+
+impl<K, V, S> ScopeStack<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// How many scopes have been pushed and not popped
+    pub fn depth(&self) -> u32 {
+        self.scope().depth()
+    }
+
+    /// Binds the key in the innermost scope, and says what happened to its old binding
+    pub fn insert(&mut self, key: K, value: V) -> InsertOutcome<'_, V> {
+        self.top().insert(key, value)
+    }
+
+    pub fn lookup<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.scope().lookup(key)
+    }
+
+    /// Gets a mutable reference to the value, if it's bound in the innermost scope
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.top().get_mut(key)
+    }
+
+    /// Removes the key from the innermost scope, like `ScopedMap::remove`
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q> + Clone,
+        Q: Hash + Eq,
+    {
+        self.top().remove(key)
+    }
+}
+
+impl<K, V, S> Drop for ScopeStack<K, V, S> {
+    fn drop(&mut self) {
+        while self.scopes.pop().is_some() {}
+    }
+}
+
+/// Safety: whatever the map borrows has to outlive it, and it can't be handed out as
+/// `ScopedMap<'static>`
+unsafe fn extend<K, V, S>(map: ScopedMap<'_, K, V, S>) -> ScopedMap<'static, K, V, S> {
+    mem::transmute(map)
+}
@@ -1,9 +1,12 @@
 //! Datastructures
 
 use crate::arena::ArenaWrapper;
+use crate::value::Value;
 use crate::BLOCK_SIZE;
 
 use ahash::RandomState;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::ptr::{self, NonNull};
@@ -58,6 +61,11 @@ impl<'a, K, V> ItemRep<'a, K, V> {
         }
     }
 
+    /// Whether they point to the same item
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+
     // FIXME: Safety: should this be unsafe?
     pub fn from_block(block: &'a Block<'a, K, V>) -> Self {
         Self {
@@ -96,32 +104,44 @@ impl<'a, T> ItemRef<'a, T> {
         }
     }
 
-    /// Safety: provide the right generation
-    pub unsafe fn promote(this: Self, generation: u32) -> Result<&'a mut T, Self>
+    /// Only items from `frozen_below` on are promoted
+    ///
+    /// Safety: provide the right generation, so that nothing else can see those items
+    pub unsafe fn promote(this: Self, frozen_below: u32) -> Result<&'a mut T, Self>
     where
         T: Item,
     {
-        debug_assert!(generation >= this.generation());
-        if this.generation() == generation {
+        if this.generation() >= frozen_below && !this.is_stable() {
             Ok(&mut *this.ptr.as_ptr())
         } else {
             Err(this)
         }
     }
 
-    /// Safety: provide the right generation
-    pub unsafe fn promote_mut(this: &mut Self, generation: u32) -> Option<&mut T>
+    /// Safety: provide the right generation, like with `promote`
+    pub unsafe fn promote_mut(this: &mut Self, frozen_below: u32) -> Option<&mut T>
     where
         T: Item,
     {
-        debug_assert!(generation >= this.generation());
-        if this.generation() == generation {
+        if this.generation() >= frozen_below && !this.is_stable() {
             Some(&mut *this.ptr.as_ptr())
         } else {
             None
         }
     }
 
+    /// Safety: nothing else can be using the item
+    pub unsafe fn promote_unchecked(this: Self) -> &'a mut T {
+        &mut *this.ptr.as_ptr()
+    }
+
+    /// Another reference to the same item
+    ///
+    /// It's still only promotable with the right generation
+    pub fn copy(this: &Self) -> Self {
+        Self::new(this.ptr)
+    }
+
     pub fn into_ref(self) -> &'a T {
         // SAFETY: pointer is always a valid reference
         // No way to get from this reference to a mutable pointer
@@ -147,6 +167,11 @@ impl<'temp, T> Deref for ItemRef<'temp, T> {
 
 pub trait Item {
     fn generation(&self) -> u32;
+
+    /// Stable items are never mutable, even from the generation that owns them
+    fn is_stable(&self) -> bool {
+        false
+    }
 }
 
 pub struct Block<'a, K, V> {
@@ -172,16 +197,33 @@ impl<'a, K, V> Block<'a, K, V> {
 
 pub struct Entry<'a, K: 'a, V: 'a> {
     pub generation: u32,
+    /// The depth of the scope it was bound in
+    pub depth: u32,
     pub key: K,
-    pub value: V,
+    /// `None` if the key was removed -- it still hides any older bindings further down the chain
+    pub value: Value<V>,
     /// Invariant: they all have the same hash
     pub next: Option<ItemRef<'a, Entry<'a, K, V>>>,
+    /// Whether it's been looked up from a scope that tracks usage
+    pub used: Cell<bool>,
+    /// Whether it's never mutated: it's in the base's arena from `insert_stable`, or its value
+    /// was moved there by `lookup_stable`
+    pub stable: bool,
+    /// Whether it hides the key's older bindings even from `lookup_outer` and `lookup_all`
+    ///
+    /// That's for the removed entries `restrict` uses to leave keys out, which aren't bound in
+    /// an outer scope of the result either.
+    pub sealed: bool,
 }
 
 impl<'a, K, V> Item for Entry<'a, K, V> {
     fn generation(&self) -> u32 {
         self.generation
     }
+
+    fn is_stable(&self) -> bool {
+        self.stable
+    }
 }
 
 pub struct ScopedMapBase<K: 'static, V: 'static, S = RandomState> {
@@ -191,9 +233,48 @@ pub struct ScopedMapBase<K: 'static, V: 'static, S = RandomState> {
 }
 
 pub struct ScopedMap<'a, K: 'a, V: 'a, S = RandomState> {
+    /// The generation new items are made with
     pub(crate) generation: u32,
+    /// The generation this scope started at -- anything older is from an outer scope
+    ///
+    /// It's only different from `generation` after a checkpoint.
+    pub(crate) scope_generation: u32,
+    /// The generations of the checkpoints that haven't been rolled back to or committed yet,
+    /// oldest first
+    ///
+    /// Items from before the last one are frozen, since the checkpoint still shares them.
+    pub(crate) checkpoints: Vec<u32>,
+    pub(crate) depth: u32,
+    /// Tells apart maps for `rollback_to`
+    pub(crate) id: u64,
     pub(crate) block_arena: ArenaWrapper<'a, Block<'a, K, V>>,
     pub(crate) entry_arena: ArenaWrapper<'a, Entry<'a, K, V>>,
     pub(crate) root: ItemRep<'a, K, V>,
     pub(crate) hasher: &'a S,
+    /// The number of visible bindings
+    pub(crate) len: usize,
+    /// Whether lookups mark the entries they find as used
+    pub(crate) track_usage: bool,
+    /// Set if this is a function scope, from `new_function_scope`
+    pub(crate) captures: Option<Captures<'a, K, V>>,
+    /// The captures of the innermost function scope this is in, if it isn't one itself
+    pub(crate) outer_captures: Option<&'a Captures<'a, K, V>>,
+    /// The base's `entry_arena`, for stable entries
+    ///
+    /// It's type-erased to keep the map covariant
+    pub(crate) base_entry_arena: NonNull<()>,
+}
+
+/// The bindings from outside a function scope that were looked up from inside it
+pub(crate) struct Captures<'a, K, V> {
+    /// The generation of the function scope -- any binding older than that is captured
+    pub generation: u32,
+    /// The captures of the function scope this one is in
+    pub outer: Option<&'a Captures<'a, K, V>>,
+    /// The captured entries, in the order they were first looked up
+    ///
+    /// They're type-erased `Entry<'a, K, V>`s, to keep the map covariant
+    pub entries: RefCell<Vec<NonNull<()>>>,
+    pub seen: RefCell<HashSet<NonNull<()>>>,
+    pub _marker: PhantomData<&'a Entry<'a, K, V>>,
 }
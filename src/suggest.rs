@@ -0,0 +1,56 @@
+//! "Did you mean" suggestions for maps with string keys
+
+use crate::iter::Entries;
+use crate::*;
+
+impl<'a, K, V, S> ScopedMap<'a, K, V, S>
+where
+    K: Eq + AsRef<str>,
+{
+    /// Finds the visible keys within `max_distance` edits of `key`, best match first
+    ///
+    /// Keys are ranked by edit distance, and then keys from inner scopes come before keys from
+    /// outer ones.
+    pub fn suggest(&self, key: &str, max_distance: usize) -> Vec<&K> {
+        let key = key.chars().collect::<Vec<_>>();
+        let mut candidates = Entries::new(&self.root)
+            .filter_map(|entry| {
+                let distance = edit_distance(&key, entry.key.as_ref(), max_distance)?;
+                Some((distance, entry.depth, &entry.key))
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|(d1, depth1, k1), (d2, depth2, k2)| {
+            d1.cmp(d2)
+                .then(depth2.cmp(depth1))
+                .then_with(|| k1.as_ref().cmp(k2.as_ref()))
+        });
+        candidates.into_iter().map(|(_, _, k)| k).collect()
+    }
+}
+
+/// The Levenshtein distance between the strings, if it's at most `max`
+fn edit_distance(a: &[char], b: &str, max: usize) -> Option<usize> {
+    let b = b.chars().collect::<Vec<_>>();
+    let len_diff = if a.len() > b.len() {
+        a.len() - b.len()
+    } else {
+        b.len() - a.len()
+    };
+    if len_diff > max {
+        return None;
+    }
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + if ca == cb { 0 } else { 1 };
+            cur[j + 1] = substitute.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        if cur.iter().all(|&d| d > max) {
+            return None;
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    Some(prev[b.len()]).filter(|&d| d <= max)
+}
@@ -47,6 +47,42 @@ random_test! {
     miri: 1_000;
 }
 
+#[cfg(not(feature = "benching"))]
+mod fnv_hasher {
+    use super::*;
+
+    random_test! {
+        name: spec_u8;
+        item: u8;
+        map: (ScopedMap<'a, u8, u32, FnvBuildHasher>, Spec<u8, u32>);
+        init: |x| (x, Spec::new());
+        hasher: FnvBuildHasher::default();
+        normally: 1_000_000;
+        miri: 100;
+    }
+
+    random_test! {
+        name: spec_collide;
+        item: BadHash;
+        map: (ScopedMap<'a, BadHash, u32, FnvBuildHasher>, Spec<BadHash, u32>);
+        init: |x| (x, Spec::new());
+        hasher: FnvBuildHasher::default();
+        normally: 1_000_000;
+        miri: 100;
+    }
+
+    #[test]
+    fn new_scope_test() {
+        let base = ScopedMapBase::with_hasher(FnvBuildHasher::default());
+        let mut map = base.make_map();
+        map.insert("a", 1);
+        let mut sub_map = map.new_scope();
+        sub_map.insert("a", 2);
+        assert_eq!(sub_map.lookup(&"a"), Some(&2));
+        assert_eq!(map.lookup(&"a"), Some(&1));
+    }
+}
+
 random_bench! {
     name: bench_10000_u8 "ScopedMap 10,000 u8";
     item: u8;
@@ -197,6 +233,829 @@ mod handwritten {
         assert_eq!(map.lookup(&5), None);
     }
 
+    #[test]
+    fn remove_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert('a', "apple");
+        map.insert('b', "banana");
+        assert_eq!(map.remove(&'b'), Some("banana"));
+        assert_eq!(map.remove(&'b'), None);
+        assert_eq!(map.lookup(&'b'), None);
+        {
+            let mut sub_map = map.new_scope();
+            // Inherited, so there's no value to give back
+            assert_eq!(sub_map.remove(&'a'), None);
+            assert_eq!(sub_map.lookup(&'a'), None);
+            {
+                let sub_sub_map = sub_map.new_scope();
+                assert_eq!(sub_sub_map.lookup(&'a'), None);
+            }
+            sub_map.insert('a', "avocado");
+            assert_eq!(sub_map.lookup(&'a'), Some(&"avocado"));
+            assert_eq!(sub_map.remove(&'a'), Some("avocado"));
+            assert_eq!(sub_map.lookup(&'a'), None);
+            assert_eq!(map.lookup(&'a'), Some(&"apple"));
+        }
+        assert_eq!(map.lookup(&'a'), Some(&"apple"));
+    }
+
+    #[test]
+    fn remove_collide_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        for i in 0..16 {
+            map.insert(BadHash(i), i);
+        }
+        let mut sub_map = map.new_scope();
+        for i in (0..16).step_by(2) {
+            assert_eq!(sub_map.remove(&BadHash(i)), None);
+        }
+        for i in 0..16 {
+            let expected = if i % 2 == 0 { None } else { Some(&i) };
+            assert_eq!(sub_map.lookup(&BadHash(i)), expected);
+            assert_eq!(map.lookup(&BadHash(i)), Some(&i));
+        }
+    }
+
+    #[test]
+    fn iter_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        for i in 0..40 {
+            map.insert(BadHash(i), u32::from(i));
+        }
+        let mut sub_map = map.new_scope();
+        for i in 0..10 {
+            sub_map.insert(BadHash(i), u32::from(i) + 100);
+            sub_map.remove(&BadHash(i + 10));
+        }
+        sub_map.insert(BadHash(50), 50);
+
+        let mut items = sub_map.iter().map(|(k, &v)| (k.0, v)).collect::<Vec<_>>();
+        items.sort();
+        let expected = (0..10)
+            .map(|i| (i, u32::from(i) + 100))
+            .chain((20..40).map(|i| (i, u32::from(i))))
+            .chain(Some((50, 50)))
+            .collect::<Vec<_>>();
+        assert_eq!(items, expected);
+
+        let mut keys = map.keys().map(|k| k.0).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, (0..40).collect::<Vec<_>>());
+        assert_eq!(map.values().sum::<u32>(), (0..40).sum::<u32>());
+    }
+
+    #[test]
+    fn len_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        assert!(map.is_empty());
+        for i in 0..40 {
+            map.insert(BadHash(i), u32::from(i));
+        }
+        map.insert(BadHash(3), 3);
+        assert_eq!(map.len(), 40);
+        {
+            let mut sub_map = map.new_scope();
+            assert_eq!(sub_map.len(), 40);
+            for i in 0..10 {
+                // Shadowing, then replacing in place
+                sub_map.insert(BadHash(i), 0);
+                sub_map.insert(BadHash(i), 1);
+            }
+            assert_eq!(sub_map.len(), 40);
+            for i in 10..20 {
+                sub_map.remove(&BadHash(i));
+                sub_map.remove(&BadHash(i));
+            }
+            assert_eq!(sub_map.len(), 30);
+            sub_map.insert(BadHash(10), 10);
+            sub_map.insert(BadHash(100), 100);
+            assert_eq!(sub_map.len(), 32);
+            assert_eq!(sub_map.iter().count(), 32);
+        }
+        assert_eq!(map.len(), 40);
+        assert_eq!(map.iter().count(), 40);
+    }
+
+    #[test]
+    fn iter_own_scope_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        for i in 0..40 {
+            map.insert(BadHash(i), u32::from(i));
+        }
+        let mut sub_map = map.new_scope();
+        assert_eq!(sub_map.iter_own_scope().count(), 0);
+        sub_map.insert(BadHash(1), 100);
+        sub_map.insert(BadHash(50), 50);
+        sub_map.insert(BadHash(50), 51);
+        sub_map.insert(BadHash(60), 60);
+        sub_map.remove(&BadHash(60));
+        sub_map.remove(&BadHash(2));
+
+        let mut items = sub_map
+            .iter_own_scope()
+            .map(|(k, &v)| (k.0, v))
+            .collect::<Vec<_>>();
+        items.sort();
+        assert_eq!(items, vec![(1, 100), (50, 51)]);
+        assert_eq!(map.iter_own_scope().count(), 40);
+    }
+
+    #[test]
+    fn diff_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        for i in 0..40 {
+            map.insert(BadHash(i), u32::from(i));
+        }
+        for i in 100..1000 {
+            map.insert(BadHash(i), u32::from(i));
+        }
+        let mut sub_map = map.new_scope();
+        sub_map.insert(BadHash(1), 100);
+        sub_map.insert(BadHash(50), 50);
+        sub_map.remove(&BadHash(2));
+        sub_map.remove(&BadHash(500));
+
+        let mut diff = map.diff(&sub_map).collect::<Vec<_>>();
+        diff.sort_by_key(|d| match d {
+            Difference::Added(k, _) | Difference::Removed(k, _) | Difference::Changed(k, _, _) => {
+                **k
+            }
+        });
+        assert_eq!(
+            diff,
+            vec![
+                Difference::Changed(&BadHash(1), Some(&1), Some(&100)),
+                Difference::Removed(&BadHash(2), Some(&2)),
+                Difference::Added(&BadHash(50), Some(&50)),
+                Difference::Removed(&BadHash(500), Some(&500)),
+            ]
+        );
+        assert_eq!(sub_map.diff(&map).count(), 4);
+        assert_eq!(sub_map.diff(&sub_map).count(), 0);
+        let sub_sub_map = sub_map.new_scope();
+        assert_eq!(sub_sub_map.diff(&sub_map).count(), 0);
+    }
+
+    #[test]
+    fn merge_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert("x", 1);
+        map.insert("y", 2);
+        map.insert("z", 3);
+        let mut then_branch = map.new_scope();
+        then_branch.insert("x", 10);
+        then_branch.remove(&"y");
+        let mut else_branch = map.new_scope();
+        else_branch.insert("x", 20);
+        else_branch.insert("w", 5);
+
+        let mut resolved = Vec::new();
+        let merged = ScopedMap::merge_from(&map, &[&then_branch, &else_branch], |k, vs| {
+            resolved.push(*k);
+            if vs.iter().all(Option::is_some) {
+                Some(vs.iter().map(|v| *v.unwrap()).sum())
+            } else {
+                None
+            }
+        });
+        drop((then_branch, else_branch));
+
+        resolved.sort();
+        assert_eq!(resolved, vec!["w", "x", "y"]);
+        assert_eq!(merged.lookup(&"x"), Some(&30));
+        assert_eq!(merged.lookup(&"y"), None);
+        assert_eq!(merged.lookup(&"z"), Some(&3));
+        assert_eq!(merged.lookup(&"w"), None);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(map.lookup(&"y"), Some(&2));
+    }
+
+    #[test]
+    fn lookup_all_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert(BadHash(0), 0);
+        map.insert(BadHash(4), 4);
+        let mut sub_map = map.new_scope();
+        sub_map.insert(BadHash(8), 8);
+        sub_map.remove(&BadHash(4));
+        let mut sub_sub_map = sub_map.new_scope();
+        sub_sub_map.insert(BadHash(0), 1);
+        sub_sub_map.insert(BadHash(0), 2);
+        sub_sub_map.insert(BadHash(4), 5);
+
+        let all = |key| sub_sub_map.lookup_all(&BadHash(key)).collect::<Vec<_>>();
+        assert_eq!(all(0), vec![(&2, 2), (&0, 0)]);
+        assert_eq!(all(4), vec![(&5, 2), (&4, 0)]);
+        assert_eq!(all(8), vec![(&8, 1)]);
+        assert_eq!(all(12), vec![]);
+
+        assert_eq!(sub_sub_map.lookup_outer(&BadHash(0)), Some(&0));
+        assert_eq!(sub_sub_map.lookup_outer(&BadHash(4)), None);
+        assert_eq!(sub_sub_map.lookup_outer(&BadHash(8)), Some(&8));
+        assert_eq!(sub_map.lookup_outer(&BadHash(4)), Some(&4));
+        assert_eq!(sub_map.lookup_outer(&BadHash(8)), None);
+    }
+
+    #[test]
+    fn depth_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert('a', "apple");
+        map.insert('b', "banana");
+        let mut sub_map = map.new_scope();
+        sub_map.insert('b', "blueberry");
+        let mut sub_sub_map = sub_map.new_scope();
+        sub_sub_map.insert('c', "cherry");
+
+        assert_eq!(map.depth(), 0);
+        assert_eq!(sub_sub_map.depth(), 2);
+        assert_eq!(sub_sub_map.lookup_with_depth(&'a'), Some((&"apple", 0)));
+        assert_eq!(sub_sub_map.lookup_with_depth(&'b'), Some((&"blueberry", 1)));
+        assert_eq!(sub_sub_map.lookup_with_depth(&'c'), Some((&"cherry", 2)));
+        assert_eq!(sub_sub_map.lookup_with_depth(&'d'), None);
+
+        assert_eq!(sub_sub_map.lookup_within(&'a', 1), None);
+        assert_eq!(sub_sub_map.lookup_within(&'a', 2), Some(&"apple"));
+        assert_eq!(sub_sub_map.lookup_within(&'b', 1), Some(&"blueberry"));
+        assert_eq!(sub_sub_map.lookup_within(&'b', 0), None);
+        assert_eq!(sub_sub_map.lookup_within(&'c', 0), Some(&"cherry"));
+    }
+
+    #[test]
+    fn entry_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        assert_eq!(map.entry("x").or_insert(1), &1);
+        assert_eq!(map.entry("x").or_insert(2), &1);
+        assert_eq!(map.entry("y").or_default(), &0);
+        map.entry("y").and_modify(|v| *v += 5).or_insert(100);
+        assert_eq!(map.lookup(&"y"), Some(&5));
+        {
+            let mut sub_map = map.new_scope();
+            match sub_map.entry("x") {
+                ScopedEntry::Occupied(mut entry) => {
+                    assert!(entry.is_inherited());
+                    assert_eq!(entry.get(), &1);
+                    *entry.get_mut() += 10;
+                    assert!(!entry.is_inherited());
+                    assert_eq!(entry.insert(20), Some(11));
+                }
+                ScopedEntry::Vacant(_) => panic!("x should be bound"),
+            }
+            match sub_map.entry("z") {
+                ScopedEntry::Occupied(_) => panic!("z shouldn't be bound"),
+                ScopedEntry::Vacant(entry) => *entry.insert(3) += 1,
+            }
+            sub_map.entry("y").and_modify(|v| *v *= 2);
+            assert_eq!(sub_map.lookup(&"x"), Some(&20));
+            assert_eq!(sub_map.lookup(&"y"), Some(&10));
+            assert_eq!(sub_map.lookup(&"z"), Some(&4));
+            assert_eq!(sub_map.len(), 3);
+            assert_eq!(map.lookup(&"x"), Some(&1));
+            assert_eq!(map.lookup(&"y"), Some(&5));
+        }
+        map.remove(&"x");
+        assert!(matches!(map.entry("x"), ScopedEntry::Vacant(_)));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn get_mut_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert(BadHash(0), vec![0]);
+        map.insert(BadHash(4), vec![4]);
+        map.get_mut(&BadHash(0)).unwrap().push(1);
+        assert_eq!(map.lookup(&BadHash(0)), Some(&vec![0, 1]));
+        {
+            let mut sub_map = map.new_scope();
+            assert_eq!(sub_map.get_mut(&BadHash(0)), None);
+            sub_map.make_mut(&BadHash(0)).unwrap().push(2);
+            sub_map.get_mut(&BadHash(0)).unwrap().push(3);
+            sub_map.make_mut(&BadHash(0)).unwrap().push(4);
+            assert_eq!(sub_map.lookup(&BadHash(0)), Some(&vec![0, 1, 2, 3, 4]));
+            assert_eq!(sub_map.lookup(&BadHash(4)), Some(&vec![4]));
+            assert_eq!(sub_map.make_mut(&BadHash(8)), None);
+            sub_map.remove(&BadHash(4));
+            assert_eq!(sub_map.make_mut(&BadHash(4)), None);
+            assert_eq!(sub_map.len(), 1);
+            assert_eq!(map.lookup(&BadHash(0)), Some(&vec![0, 1]));
+        }
+        assert_eq!(map.lookup(&BadHash(0)), Some(&vec![0, 1]));
+        assert_eq!(map.lookup(&BadHash(4)), Some(&vec![4]));
+    }
+
+    #[test]
+    fn insert_outcome_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        assert_eq!(map.insert(BadHash(0), 0), InsertOutcome::Fresh);
+        assert_eq!(map.insert(BadHash(4), 4), InsertOutcome::Fresh);
+        assert_eq!(map.insert(BadHash(0), 1), InsertOutcome::Replaced(0));
+        let mut sub_map = map.new_scope();
+        assert_eq!(
+            sub_map.insert(BadHash(4), 5),
+            InsertOutcome::Shadowed(Some(&4))
+        );
+        assert_eq!(sub_map.insert(BadHash(4), 6), InsertOutcome::Replaced(5));
+        assert_eq!(sub_map.insert(BadHash(8), 8), InsertOutcome::Fresh);
+        assert_eq!(sub_map.insert(BadHash(1), 1), InsertOutcome::Fresh);
+        sub_map.remove(&BadHash(0));
+        assert_eq!(sub_map.insert(BadHash(0), 2), InsertOutcome::Fresh);
+    }
+
+    #[test]
+    fn try_insert_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        assert_eq!(map.try_insert(BadHash(0), 0), Ok(&0));
+        assert_eq!(map.try_insert(BadHash(4), 4), Ok(&4));
+        assert_eq!(
+            map.try_insert(BadHash(0), 1),
+            Err(DuplicateInScope {
+                key: BadHash(0),
+                value: 1
+            })
+        );
+        assert_eq!(map.lookup(&BadHash(0)), Some(&0));
+        let mut sub_map = map.new_scope();
+        assert_eq!(sub_map.try_insert(BadHash(0), 2), Ok(&2));
+        assert!(sub_map.try_insert(BadHash(0), 3).is_err());
+        assert_eq!(sub_map.lookup(&BadHash(0)), Some(&2));
+        sub_map.remove(&BadHash(4));
+        assert_eq!(sub_map.try_insert(BadHash(4), 5), Ok(&5));
+        assert_eq!(map.lookup(&BadHash(0)), Some(&0));
+    }
+
+    #[test]
+    fn unused_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.set_track_usage(true);
+        map.insert("x", 0);
+        map.insert("y", 1);
+        map.insert("z", 2);
+        assert_eq!(map.lookup(&"x"), Some(&0));
+        {
+            let mut sub_map = map.new_scope();
+            sub_map.insert("y", 3);
+            sub_map.insert("w", 4);
+            assert_eq!(sub_map.lookup_outer(&"y"), Some(&1));
+            assert_eq!(sub_map.lookup(&"y"), Some(&3));
+            let mut unused = Vec::new();
+            sub_map.finish(|k, v| unused.push((*k, v.copied())));
+            assert_eq!(unused, vec![("w", Some(4))]);
+        }
+        let mut unused = map.unused().collect::<Vec<_>>();
+        unused.sort();
+        assert_eq!(unused, vec![(&"z", Some(&2))]);
+
+        let mut untracked = map.new_scope();
+        untracked.set_track_usage(false);
+        untracked.lookup(&"z");
+        assert_eq!(map.unused().count(), 1);
+    }
+
+    #[test]
+    fn suggest_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert("count", 0);
+        map.insert("counter", 1);
+        map.insert("mount", 2);
+        map.insert("total", 3);
+        let mut sub_map = map.new_scope();
+        sub_map.insert("coutn", 4);
+        sub_map.insert("amount", 5);
+        sub_map.remove(&"mount");
+        assert_eq!(
+            sub_map.suggest("cont", 3),
+            vec![&"count", &"coutn", &"amount", &"counter"]
+        );
+        assert_eq!(sub_map.suggest("mount", 1), vec![&"amount", &"count"]);
+        assert_eq!(map.suggest("mount", 1), vec![&"mount", &"count"]);
+        assert!(sub_map.suggest("xyz", 1).is_empty());
+    }
+
+    #[test]
+    fn restrict_outer_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        for i in 0..16 {
+            map.insert(BadHash(i), i);
+        }
+        let mut sub_map = map.new_scope();
+        sub_map.insert(BadHash(4), 100);
+
+        // BadHash(8) shares a chain with BadHash(4), and BadHash(1) doesn't, but neither is bound
+        // in the restricted map or outside it
+        let mut restricted = sub_map.restrict([BadHash(4)].iter());
+        for key in &[BadHash(8), BadHash(1)] {
+            assert_eq!(restricted.lookup(key), None);
+            assert_eq!(restricted.lookup_outer(key), None);
+            assert_eq!(restricted.lookup_all(key).count(), 0);
+        }
+        assert_eq!(restricted.lookup_outer(&BadHash(4)), Some(&100));
+        assert_eq!(
+            restricted.lookup_all(&BadHash(4)).collect::<Vec<_>>(),
+            vec![(&100, 1), (&4, 0)]
+        );
+        {
+            let child = restricted.new_scope();
+            assert_eq!(child.lookup_outer(&BadHash(8)), None);
+            assert_eq!(child.lookup_all(&BadHash(8)).count(), 0);
+        }
+
+        assert_eq!(restricted.insert(BadHash(8), 80), InsertOutcome::Fresh);
+        assert_eq!(restricted.lookup_outer(&BadHash(8)), None);
+        assert_eq!(
+            restricted.lookup_all(&BadHash(8)).collect::<Vec<_>>(),
+            vec![(&80, 2)]
+        );
+        assert_eq!(sub_map.lookup_all(&BadHash(8)).count(), 1);
+    }
+
+    #[test]
+    fn restrict_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        for i in 0..64 {
+            map.insert(BadHash(i), i);
+        }
+        let mut sub_map = map.new_scope();
+        sub_map.insert(BadHash(4), 100);
+        sub_map.remove(&BadHash(8));
+
+        let keys = [
+            BadHash(4),
+            BadHash(8),
+            BadHash(12),
+            BadHash(13),
+            BadHash(99),
+        ];
+        let restricted = sub_map.restrict(keys.iter());
+        assert_eq!(restricted.len(), 3);
+        assert_eq!(restricted.lookup(&BadHash(4)), Some(&100));
+        assert_eq!(restricted.lookup(&BadHash(8)), None);
+        assert_eq!(restricted.lookup(&BadHash(12)), Some(&12));
+        assert_eq!(restricted.lookup(&BadHash(13)), Some(&13));
+        assert_eq!(restricted.lookup(&BadHash(0)), None);
+        assert_eq!(restricted.lookup(&BadHash(16)), None);
+        let mut items = restricted
+            .iter()
+            .map(|(k, v)| (k.0, *v))
+            .collect::<Vec<_>>();
+        items.sort();
+        assert_eq!(items, vec![(4, 100), (12, 12), (13, 13)]);
+        // The bindings are shared
+        assert!(std::ptr::eq(
+            restricted.lookup(&BadHash(12)).unwrap(),
+            map.lookup(&BadHash(12)).unwrap()
+        ));
+
+        let even = sub_map.retain(|k, _| k.0 % 2 == 0);
+        assert_eq!(even.len(), 31);
+        assert_eq!(even.lookup(&BadHash(4)), Some(&100));
+        assert_eq!(even.lookup(&BadHash(5)), None);
+        assert_eq!(even.lookup(&BadHash(6)), Some(&6));
+
+        let doubled = sub_map.map_values(|_, v| v * 2);
+        assert_eq!(doubled.len(), 63);
+        assert_eq!(doubled.lookup(&BadHash(4)), Some(&200));
+        assert_eq!(doubled.lookup(&BadHash(8)), None);
+        assert_eq!(doubled.lookup(&BadHash(9)), Some(&18));
+    }
+
+    #[test]
+    fn function_scope_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert("x", 0);
+        map.insert("y", 1);
+        map.insert("unused", 2);
+        let mut function = map.new_function_scope();
+        function.insert("a", 3);
+        {
+            let mut block = function.new_scope();
+            block.insert("b", 4);
+            block.lookup(&"y");
+            block.lookup(&"a");
+            block.lookup(&"b");
+            block.lookup(&"x");
+            block.lookup(&"y");
+            block.lookup(&"nonexistent");
+
+            let mut inner = block.new_function_scope();
+            inner.insert("x", 5);
+            inner.lookup(&"x");
+            inner.lookup(&"a");
+            inner.lookup_outer(&"x");
+            inner.new_scope().lookup(&"unused");
+            assert_eq!(inner.captured(), vec![&"a", &"x", &"unused"]);
+        }
+        function.lookup(&"a");
+        assert_eq!(function.captured(), vec![&"y", &"x", &"unused"]);
+        assert!(map.captured().is_empty());
+    }
+
+    #[test]
+    fn function_scope_mutation_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert("x", 1);
+        map.insert("y", 2);
+        let mut function = map.new_function_scope();
+        *function.make_mut(&"x").unwrap() += 1;
+        if let ScopedEntry::Occupied(mut entry) = function.entry("y") {
+            *entry.get_mut() += 1;
+        }
+        assert_eq!(function.captured(), vec![&"x", &"y"]);
+        assert_eq!(function.lookup(&"x"), Some(&2));
+        assert_eq!(map.lookup(&"x"), Some(&1));
+    }
+
+    #[test]
+    fn stable_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        let a = map.insert_stable("a", 1);
+        map.insert("b", 2);
+        let b = map.lookup_stable(&"b").unwrap();
+        assert_eq!(map.get_mut(&"b"), None);
+        assert_eq!(map.insert("a", 3), InsertOutcome::Redefined(Some(&1)));
+        assert_eq!(map.insert("b", 4), InsertOutcome::Redefined(Some(&2)));
+        assert_eq!((*a, *b), (1, 2));
+        assert_eq!(map.lookup(&"a"), Some(&3));
+        assert_eq!(map.lookup(&"b"), Some(&4));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.iter_own_scope().count(), 2);
+
+        let s = map.insert_stable("s", 5);
+        assert_eq!(map.get_mut(&"s"), None);
+        *map.make_mut(&"s").unwrap() += 1;
+        assert_eq!(map.lookup(&"s"), Some(&6));
+        assert_eq!(map.remove(&"s"), Some(6));
+        assert_eq!(map.remove(&"s"), None);
+        assert_eq!(map.lookup(&"s"), None);
+        assert_eq!(*s, 5);
+        assert_eq!(map.len(), 2);
+
+        let (c, inherited);
+        {
+            let mut sub_map = map.new_scope();
+            c = sub_map.insert_stable("c", 7);
+            inherited = sub_map.lookup_stable(&"a").unwrap();
+            sub_map.insert("c", 8);
+        }
+        assert_eq!((*c, *inherited), (7, 3));
+        assert_eq!(map.lookup(&"c"), None);
+    }
+
+    #[test]
+    fn insert_lazy_test() {
+        use std::cell::Cell;
+        let computed = Cell::new(0);
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert_lazy("a", || {
+            computed.set(computed.get() + 1);
+            1
+        });
+        map.insert_lazy("b", || panic!("never looked up"));
+        map.insert_lazy("c", || 3);
+        assert_eq!(map.insert_lazy("c", || 4), InsertOutcome::Replaced(3));
+        assert_eq!(computed.get(), 0);
+        assert_eq!(map.len(), 3);
+        {
+            let sub_map = map.new_scope();
+            assert_eq!(sub_map.lookup(&"a"), Some(&1));
+            assert_eq!(computed.get(), 1);
+        }
+        assert_eq!(map.lookup(&"a"), Some(&1));
+        assert_eq!(computed.get(), 1);
+        *map.get_mut(&"a").unwrap() += 1;
+        assert_eq!(map.lookup(&"a"), Some(&2));
+        assert_eq!(map.lookup(&"c"), Some(&4));
+        assert_eq!(map.remove(&"c"), Some(4));
+        assert_eq!(computed.get(), 1);
+
+        // Only reading the value computes it
+        map.insert_lazy("d", || panic!("never looked up"));
+        {
+            let mut sub_map = map.new_scope();
+            assert_eq!(sub_map.remove(&"d"), None);
+            assert_eq!(sub_map.lookup(&"d"), None);
+        }
+        {
+            let mut sub_map = map.new_scope();
+            assert!(sub_map.try_insert("d", 5).is_ok());
+            assert!(sub_map.try_insert("d", 6).is_err());
+        }
+        {
+            let mut sub_map = map.new_scope();
+            assert_eq!(sub_map.insert("d", 5), InsertOutcome::Shadowed(None));
+            assert_eq!(
+                sub_map.insert_lazy("a", || 7),
+                InsertOutcome::Shadowed(Some(&2))
+            );
+            assert_eq!(sub_map.insert_lazy("d", || 6), InsertOutcome::Replaced(5));
+        }
+        map.set_track_usage(true);
+        map.insert_lazy("e", || panic!("never looked up"));
+        assert!(map.unused().any(|(k, v)| *k == "e" && v.is_none()));
+        {
+            let mut sub_map = map.new_scope();
+            sub_map.insert_lazy("f", || panic!("never looked up"));
+            let diff = map.diff(&sub_map).collect::<Vec<_>>();
+            assert_eq!(diff, vec![Difference::Added(&"f", None)]);
+            sub_map.finish(|k, v| assert_eq!((*k, v), ("f", None)));
+        }
+        assert!(
+            std::mem::size_of::<crate::value::Value<u32>>() <= 2 * std::mem::size_of::<usize>()
+        );
+    }
+
+    #[test]
+    fn checkpoint_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert("a", 1);
+        map.insert("b", 2);
+        let start = map.checkpoint();
+        assert_eq!(map.insert("a", 10), InsertOutcome::Redefined(Some(&1)));
+        assert_eq!(map.get_mut(&"b"), None);
+        *map.make_mut(&"b").unwrap() += 10;
+        assert_eq!(map.remove(&"b"), Some(12));
+        map.insert("c", 3);
+        assert!(map.try_insert("a", 0).is_err());
+        assert_eq!(map.lookup_with_depth(&"a"), Some((&10, 0)));
+        assert_eq!(map.iter_own_scope().count(), 2);
+        let after = map.checkpoint();
+        map.insert("d", 4);
+
+        map.rollback_to(after);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.lookup(&"a"), Some(&10));
+        assert_eq!(map.lookup(&"b"), None);
+        assert_eq!(map.lookup(&"d"), None);
+        // `start` is still outstanding
+        assert_eq!(map.get_mut(&"c"), Some(&mut 3));
+        assert_eq!(map.insert("c", 30), InsertOutcome::Replaced(3));
+
+        map.rollback_to(start);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.lookup(&"a"), Some(&1));
+        assert_eq!(map.lookup(&"b"), Some(&2));
+        assert_eq!(map.lookup(&"c"), None);
+        // Nothing's outstanding, so it's all changed in place again
+        *map.get_mut(&"b").unwrap() += 10;
+        assert_eq!(map.insert("a", 10), InsertOutcome::Replaced(1));
+        assert_eq!(map.remove(&"b"), Some(12));
+        assert_eq!(map.lookup_all(&"a").count(), 1);
+
+        let start = map.checkpoint();
+        map.insert("b", 20);
+        let stable = map.lookup_stable(&"a").unwrap();
+        assert_eq!(map.get_mut(&"a"), None);
+        map.rollback_to(start);
+        assert_eq!(map.lookup(&"b"), None);
+        {
+            let sub_map = map.new_scope();
+            assert_eq!(sub_map.lookup_within(&"a", 0), None);
+            assert_eq!(sub_map.lookup_within(&"a", 1), Some(&10));
+        }
+        drop(map);
+        assert_eq!(*stable, 10);
+    }
+
+    #[test]
+    fn checkpoint_commit_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert("a", 1);
+        let outer = map.checkpoint();
+        map.insert("b", 2);
+        let inner = map.checkpoint();
+        map.insert("c", 3);
+
+        map.commit(outer);
+        // `inner` still shares "b"
+        assert_eq!(map.get_mut(&"b"), None);
+        assert_eq!(map.get_mut(&"c"), Some(&mut 3));
+        map.insert("b", 20);
+        map.rollback_to(inner);
+        assert_eq!(map.lookup(&"b"), Some(&2));
+        assert_eq!(map.lookup(&"c"), None);
+
+        assert_eq!(map.insert("b", 20), InsertOutcome::Replaced(2));
+        assert_eq!(map.remove(&"a"), Some(1));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "checkpoint was undone by rolling back to an older one")]
+    fn checkpoint_undone_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        let outer = map.checkpoint();
+        map.insert("a", 1);
+        let inner = map.checkpoint();
+        map.rollback_to(outer);
+        map.rollback_to(inner);
+    }
+
+    #[test]
+    #[should_panic(expected = "checkpoint is from a different map")]
+    fn checkpoint_other_map_test() {
+        let base = ScopedMapBase::<u8, u8>::new();
+        let map = base.make_map();
+        let mut sub_map = map.new_scope();
+        let mut other = map.new_scope();
+        let checkpoint = other.checkpoint();
+        sub_map.rollback_to(checkpoint);
+    }
+
+    #[test]
+    fn transaction_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert("a", 1);
+        {
+            let mut tx = map.transaction();
+            tx.insert("a", 2);
+            tx.insert("b", 3);
+            assert_eq!(tx.lookup(&"a"), Some(&2));
+        }
+        assert_eq!(map.lookup(&"a"), Some(&1));
+        assert_eq!(map.lookup(&"b"), None);
+
+        let mut tx = map.transaction();
+        tx.insert("b", 3);
+        {
+            let mut inner = tx.transaction();
+            inner.remove(&"a");
+            inner.rollback();
+        }
+        tx.commit();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.lookup(&"a"), Some(&1));
+        assert_eq!(map.lookup(&"b"), Some(&3));
+        assert_eq!(map.iter_own_scope().count(), 2);
+    }
+
+    #[test]
+    fn transaction_commit_test() {
+        let base = ScopedMapBase::new();
+        let mut map = base.make_map();
+        map.insert("a", 1);
+        map.insert("b", 2);
+        let mut tx = map.transaction();
+        assert_eq!(tx.get_mut(&"a"), None);
+        tx.insert("c", 3);
+        tx.commit();
+
+        // "a" and "b" are from before the transaction, but nothing can roll back to that now
+        *map.get_mut(&"a").unwrap() += 10;
+        assert_eq!(map.insert("a", 20), InsertOutcome::Replaced(11));
+        assert_eq!(map.remove(&"b"), Some(2));
+        assert_eq!(map.lookup_all(&"a").count(), 1);
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn scope_stack_test() {
+        let mut stack = ScopeStack::new();
+        stack.insert("a", 1);
+        stack.insert("b", 2);
+        for i in 0..3 {
+            stack.push_scope();
+            assert_eq!(stack.depth(), 1);
+            assert_eq!(stack.insert("a", 10 + i), InsertOutcome::Shadowed(Some(&1)));
+            stack.insert("c", 3);
+            *stack.get_mut(&"c").unwrap() += 1;
+            assert_eq!(stack.remove(&"b"), None);
+            stack.push_scope();
+            assert_eq!(stack.lookup(&"a"), Some(&(10 + i)));
+            assert_eq!(stack.lookup(&"b"), None);
+            assert_eq!(stack.scope().lookup_outer(&"c"), Some(&4));
+            stack.pop_scope();
+            stack.pop_scope();
+        }
+        assert_eq!(stack.depth(), 0);
+        assert_eq!(stack.lookup(&"a"), Some(&1));
+        assert_eq!(stack.lookup(&"b"), Some(&2));
+        assert_eq!(stack.lookup(&"c"), None);
+        assert_eq!(stack.scope().len(), 2);
+    }
+
     #[test]
     fn another_simple_test() {
         let base = ScopedMapBase::new();
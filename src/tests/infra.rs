@@ -7,7 +7,7 @@ use rand::distributions::{Distribution, Standard};
 use rand::prelude::*;
 use std::borrow::Borrow;
 use std::fmt::Debug;
-use std::hash::{Hash, Hasher};
+use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
 use std::marker::PhantomData;
 use std::rc::Rc;
 
@@ -17,12 +17,15 @@ pub(crate) trait Map<'a, K, V> {
         K: Borrow<Q>,
         Q: Eq + Hash;
     fn insert(&mut self, key: K, value: V);
+    fn remove(&mut self, key: &K);
+    fn len(&self) -> usize;
     fn new_scope(&'a self) -> Self;
 }
 
-impl<'a, K: 'static, V: 'static> Map<'a, K, V> for ScopedMap<'a, K, V>
+impl<'a, K: 'static, V: 'static, S> Map<'a, K, V> for ScopedMap<'a, K, V, S>
 where
-    K: Eq + Hash,
+    K: Clone + Eq + Hash,
+    S: BuildHasher,
 {
     fn lookup<'map, 'key, Q>(&'map self, key: &'key Q) -> Option<&'map V>
     where
@@ -36,6 +39,14 @@ where
         self.insert(key, value);
     }
 
+    fn remove(&mut self, key: &K) {
+        self.remove(key);
+    }
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+
     fn new_scope(&'a self) -> Self {
         self.new_scope()
     }
@@ -45,7 +56,7 @@ impl<'a, K, V, S> Map<'a, K, V> for im_rc::HashMap<K, V, S>
 where
     K: Clone + Eq + Hash,
     V: Clone,
-    S: std::hash::BuildHasher,
+    S: BuildHasher,
 {
     fn lookup<'map, 'key, Q>(&'map self, key: &'key Q) -> Option<&'map V>
     where
@@ -59,6 +70,14 @@ where
         self.insert(key, value);
     }
 
+    fn remove(&mut self, key: &K) {
+        self.remove(key);
+    }
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+
     fn new_scope(&'a self) -> Self {
         self.clone()
     }
@@ -67,30 +86,69 @@ where
 /// A functional specification for the map
 pub struct Spec<K, V> {
     head: Option<Rc<Node<K, V>>>,
+    /// The number of keys that aren't removed
+    ///
+    /// Keeping it means walking the list on every insert, so it's only kept in tests, not when
+    /// benchmarking.
+    len: usize,
 }
 
 struct Node<K, V> {
     key: K,
-    value: V,
+    /// `None` if the key was removed
+    value: Option<V>,
     next: Option<Rc<Node<K, V>>>,
 }
 
-impl<K, V> Spec<K, V> {
+impl<K: Eq, V> Spec<K, V> {
     pub fn new() -> Self {
-        Self { head: None }
+        Self { head: None, len: 0 }
+    }
+
+    fn lookup_key(&self, key: &K) -> Option<&V> {
+        let mut node = &self.head;
+        while let Some(n) = node {
+            if n.key == *key {
+                return n.value.as_ref();
+            }
+            node = &n.next;
+        }
+        None
     }
 }
 
-impl<'a, K: Eq, V> Map<'a, K, V> for Spec<K, V> {
+impl<'a, K: Eq + Clone, V> Map<'a, K, V> for Spec<K, V> {
     fn insert(&mut self, key: K, value: V) {
+        #[cfg(not(feature = "benching"))]
+        {
+            if self.lookup_key(&key).is_none() {
+                self.len += 1;
+            }
+        }
         let new_node = Node {
             key,
-            value,
+            value: Some(value),
             next: self.head.take(),
         };
         self.head = Some(Rc::new(new_node));
     }
 
+    fn remove(&mut self, key: &K) {
+        if self.lookup_key(key).is_some() {
+            self.len -= 1;
+            let new_node = Node {
+                key: key.clone(),
+                value: None,
+                next: self.head.take(),
+            };
+            self.head = Some(Rc::new(new_node));
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
     fn lookup<'map, 'key, Q>(&'map self, key: &'key Q) -> Option<&'map V>
     where
         K: Borrow<Q>,
@@ -99,7 +157,7 @@ impl<'a, K: Eq, V> Map<'a, K, V> for Spec<K, V> {
         let mut node = &self.head;
         while let Some(n) = node {
             if n.key.borrow() == key {
-                return Some(&n.value);
+                return n.value.as_ref();
             }
             node = &n.next;
         }
@@ -109,6 +167,7 @@ impl<'a, K: Eq, V> Map<'a, K, V> for Spec<K, V> {
     fn new_scope(&'a self) -> Self {
         Self {
             head: self.head.clone(),
+            len: self.len,
         }
     }
 }
@@ -125,6 +184,15 @@ where
         self.0.insert(key.clone(), value.clone());
         self.1.insert(key, value);
     }
+    fn remove(&mut self, key: &K) {
+        self.0.remove(key);
+        self.1.remove(key);
+    }
+    fn len(&self) -> usize {
+        let (a, b) = (self.0.len(), self.1.len());
+        assert_eq!(a, b);
+        a
+    }
     fn lookup<'map, 'key, Q>(&'map self, key: &'key Q) -> Option<&'map V>
     where
         K: Borrow<Q>,
@@ -145,6 +213,7 @@ pub enum MapCmd<K> {
     OldScope,
     Lookup(K, u32), // u32 depth: 0 is current
     Insert(K, u32), // u32 value
+    Remove(K),
 }
 
 impl<K> MapCmd<K> {
@@ -155,6 +224,7 @@ impl<K> MapCmd<K> {
             OldScope => OldScope,
             Lookup(k, v) => Lookup(f(k), v),
             Insert(k, v) => Insert(f(k), v),
+            Remove(k) => Remove(f(k)),
         }
     }
 }
@@ -163,6 +233,8 @@ pub struct RandCmds<T, D> {
     rng: SmallRng,
     depth: u32,
     dist: D,
+    /// Whether to make `MapCmd::Remove`s too
+    removes: bool,
     _marker: PhantomData<T>,
 }
 
@@ -172,9 +244,21 @@ impl<T, D> RandCmds<T, D> {
             rng: SmallRng::from_entropy(),
             depth: 1,
             dist,
+            removes: false,
             _marker: PhantomData,
         }
     }
+
+    /// Like `new`, but some of the lookups are removes instead
+    ///
+    /// The benchmarks don't use this, so their workload stays the same.
+    #[cfg(not(feature = "benching"))]
+    pub fn with_removes(dist: D) -> Self {
+        Self {
+            removes: true,
+            ..Self::new(dist)
+        }
+    }
 }
 
 impl<T, D> Iterator for RandCmds<T, D>
@@ -186,6 +270,8 @@ where
         let x = self.rng.gen::<f32>();
         const SCOPE_FRAC: f32 = 0.005;
         const INSERT_FRAC: f32 = 0.05;
+        const REMOVE_FRAC: f32 = 0.01;
+        let remove_frac = if self.removes { REMOVE_FRAC } else { 0. };
 
         if x < SCOPE_FRAC {
             // Change scope
@@ -202,6 +288,10 @@ where
             let key = self.dist.sample(&mut self.rng);
             let value = self.rng.gen::<u32>();
             Some(MapCmd::Insert(key, value))
+        } else if x > 1. - INSERT_FRAC - remove_frac {
+            // Remove
+            let key = self.dist.sample(&mut self.rng);
+            Some(MapCmd::Remove(key))
         } else {
             // Lookup
             let key = self.dist.sample(&mut self.rng);
@@ -263,6 +353,14 @@ macro_rules! random_base {
                     }
                     MapCmd::Insert(key, value) => {
                         map_list.current.insert(key, value);
+                        // Checks the maps agree, but only in tests, so benchmarks just insert
+                        #[cfg(not(feature = "benching"))]
+                        let _ = map_list.current.len();
+                    }
+                    MapCmd::Remove(key) => {
+                        map_list.current.remove(&key);
+                        #[cfg(not(feature = "benching"))]
+                        let _ = map_list.current.len();
                     }
                 }
             }
@@ -278,6 +376,25 @@ macro_rules! random_test {
         init: $init:expr;
         normally: $normal:expr;
         miri: $miri:expr;
+    ) => {
+        random_test! {
+            name: $name;
+            item: $itemty;
+            map: $maptype;
+            init: $init;
+            hasher: ahash::RandomState::new();
+            normally: $normal;
+            miri: $miri;
+        }
+    };
+    (
+        name: $name:ident;
+        item: $itemty:ty;
+        map: $maptype:ty;
+        init: $init:expr;
+        hasher: $hasher:expr;
+        normally: $normal:expr;
+        miri: $miri:expr;
     ) => {
         #[cfg(not(feature = "benching"))]
         mod $name {
@@ -293,8 +410,8 @@ macro_rules! random_test {
                 #[cfg(miri)]
                 const ITERS: usize = $miri;
 
-                let mut iter = RandCmds::new(Standard).take(ITERS);
-                let map_base = ScopedMapBase::<$itemty, u32>::new();
+                let mut iter = RandCmds::with_removes(Standard).take(ITERS);
+                let map_base = ScopedMapBase::<$itemty, u32, _>::with_hasher($hasher);
                 let map = map_base.make_map();
                 do_cmds(
                     List {
@@ -394,6 +511,30 @@ macro_rules! random_bench_zipf {
     };
 }
 
+/// A deterministic hasher (FNV-1a), for testing maps with a non-default hasher
+pub struct Fnv(u64);
+
+pub type FnvBuildHasher = BuildHasherDefault<Fnv>;
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
 /// A struct for testing hash collisions
 ///
 /// It's got a `u16`, but only hashes 2 of the bits
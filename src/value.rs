@@ -0,0 +1,170 @@
+//! The values in entries, which might be computed lazily
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr::NonNull;
+
+/// The value in an entry
+///
+/// It works like an `Option<V>`, where `None` means the key was removed, except that a lazy value
+/// is computed the first time it's looked at.
+pub enum Value<V> {
+    Removed,
+    Here(V),
+    /// Boxed, so it doesn't make every entry bigger
+    Lazy(Box<Lazy<V>>),
+    /// Moved to a stable entry by `lookup_stable` -- this points to it
+    Moved(NonNull<()>),
+}
+
+impl<V> From<Option<V>> for Value<V> {
+    fn from(value: Option<V>) -> Self {
+        match value {
+            Some(value) => Value::Here(value),
+            None => Value::Removed,
+        }
+    }
+}
+
+impl<V> Value<V> {
+    pub fn lazy<'f, F: FnOnce() -> V + 'f>(f: F) -> Self {
+        Value::Lazy(Box::new(Lazy::new(f)))
+    }
+
+    /// Safety: the value has to outlive this, and it can't ever be changed
+    pub unsafe fn moved(value: &V) -> Self {
+        Value::Moved(NonNull::from(value).cast())
+    }
+
+    pub fn is_some(&self) -> bool {
+        !matches!(self, Value::Removed)
+    }
+
+    /// Gets the value, computing it first if it's lazy
+    pub fn as_ref(&self) -> Option<&V> {
+        match self {
+            Value::Removed => None,
+            Value::Here(value) => Some(value),
+            Value::Lazy(lazy) => Some(lazy.force()),
+            // SAFETY: it outlives us, from `moved`
+            Value::Moved(value) => Some(unsafe { value.cast().as_ref() }),
+        }
+    }
+
+    /// Gets the value if it's there, without computing it if it's lazy
+    pub fn computed(&self) -> Option<&V> {
+        match self {
+            Value::Lazy(lazy) => lazy.computed(),
+            _ => self.as_ref(),
+        }
+    }
+
+    pub fn as_mut(&mut self) -> Option<&mut V> {
+        self.materialize();
+        match self {
+            Value::Here(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn take(&mut self) -> Option<V> {
+        self.materialize();
+        match mem::replace(self, Value::Removed) {
+            Value::Here(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn replace(&mut self, value: V) -> Option<V> {
+        let old_value = self.take();
+        *self = Value::Here(value);
+        old_value
+    }
+
+    pub fn into_option(mut self) -> Option<V> {
+        self.take()
+    }
+
+    /// Moves a lazy value into `Here`, computing it first if it has to
+    fn materialize(&mut self) {
+        match self {
+            Value::Lazy(_) => {
+                if let Value::Lazy(lazy) = mem::replace(self, Value::Removed) {
+                    *self = Value::Here((*lazy).into_value());
+                }
+            }
+            Value::Moved(_) => unreachable!("values moved by lookup_stable are never changed"),
+            _ => {}
+        }
+    }
+}
+
+/// A value that's computed the first time it's needed
+///
+/// Both the thunk and the value are boxed and type-erased, so that entries stay covariant in `V`
+/// even though the value's filled in through a shared reference.
+pub struct Lazy<V> {
+    /// A `Box<dyn FnOnce() -> V>`, until it's run
+    thunk: Cell<Option<NonNull<()>>>,
+    /// A `Box<V>`, once the thunk's been run
+    value: Cell<Option<NonNull<()>>>,
+    _marker: PhantomData<V>,
+}
+
+type Thunk<V> = Box<dyn FnOnce() -> V>;
+
+impl<V> Lazy<V> {
+    fn new<'f, F: FnOnce() -> V + 'f>(f: F) -> Self {
+        let thunk: Box<Box<dyn FnOnce() -> V + 'f>> = Box::new(Box::new(f));
+        Self {
+            thunk: Cell::new(Some(NonNull::from(Box::leak(thunk)).cast())),
+            value: Cell::new(None),
+            _marker: PhantomData,
+        }
+    }
+
+    fn force(&self) -> &V {
+        if self.value.get().is_none() {
+            let thunk = self
+                .thunk
+                .take()
+                .expect("lazy value needs itself to be computed");
+            // SAFETY: it's the thunk from `new`, and we took it out, so it only runs once. It
+            // might really only live for 'f, but the entry it's in doesn't outlive that
+            let thunk = unsafe { Box::from_raw(thunk.cast::<Thunk<V>>().as_ptr()) };
+            let value = Box::new(thunk());
+            self.value.set(Some(NonNull::from(Box::leak(value)).cast()));
+        }
+        self.computed().unwrap()
+    }
+
+    /// The value, if the thunk's been run
+    fn computed(&self) -> Option<&V> {
+        // SAFETY: it's a `Box<V>` that's never changed or freed while we're borrowed
+        self.value
+            .get()
+            .map(|value| unsafe { value.cast().as_ref() })
+    }
+
+    fn into_value(self) -> V {
+        self.force();
+        let value = self.value.take().unwrap();
+        // SAFETY: it's the `Box<V>` from `force`, and we took it out so it's not dropped again
+        *unsafe { Box::from_raw(value.cast::<V>().as_ptr()) }
+    }
+}
+
+impl<V> Drop for Lazy<V> {
+    fn drop(&mut self) {
+        // SAFETY: they're the boxes from `new` and `force`
+        unsafe {
+            if let Some(thunk) = self.thunk.take() {
+                mem::drop(Box::from_raw(thunk.cast::<Thunk<V>>().as_ptr()));
+            }
+            if let Some(value) = self.value.take() {
+                mem::drop(Box::from_raw(value.cast::<V>().as_ptr()));
+            }
+        }
+    }
+}